@@ -0,0 +1,301 @@
+use crate::error::{Error, Result};
+use crate::sfr::sfr_address;
+
+/// Length of an 8051 instruction in bytes, `None` for the reserved opcode 0xA5
+pub fn opcode_len(opcode: u8) -> Option<usize> {
+    let len = match (opcode >> 4, opcode & 0x0F) {
+        (0xA, 0x5) => return None,
+        // AJMP, ACALL
+        (_, 0x1) => 2,
+        // JBC, JB, JNB, LJMP, LCALL, ORL/ANL/XRL direct, #data, MOV DPTR, #data16
+        (0x1..=0x3, 0x0) | (0x0..=0x1, 0x2) | (0x4..=0x6, 0x3) | (0x9, 0x0) => 3,
+        // MOV direct, #data, MOV direct, direct, CJNE, DJNZ direct
+        (0x7..=0x8, 0x5) | (0xB, 0x4..=0xF) | (0xD, 0x5) => 3,
+        (0x4..=0xD, 0x0) | (0x4..=0xD, 0x2) => 2,
+        (_, 0x0) | (_, 0x2) | (_, 0x3) => 1,
+        (0x2..=0x7 | 0x9, 0x4) => 2,
+        (_, 0x4) => 1,
+        (_, 0x5) => 2,
+        // MOV @Ri/Rn, #data, MOV direct, @Ri/Rn, MOV @Ri/Rn, direct, DJNZ Rn
+        (0x7 | 0x8 | 0xA, _) | (0xD, 0x8..=0xF) => 2,
+        _ => 1,
+    };
+    Some(len)
+}
+
+/// Whether the instruction changes the program counter
+///
+/// The debug core executes injected instructions in place, so jumps, calls
+/// and returns would leave it running somewhere else.
+pub fn is_branch(opcode: u8) -> bool {
+    matches!(
+        (opcode >> 4, opcode & 0x0F),
+        (_, 0x1)
+            | (0x0..=0x3, 0x2)
+            | (0x1..=0x8, 0x0)
+            | (0x7, 0x3)
+            | (0xB, 0x4..=0xF)
+            | (0xD, 0x5)
+            | (0xD, 0x8..=0xF)
+    )
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Operand {
+    A,
+    Ab,
+    C,
+    Dptr,
+    AtDptr,
+    AtADptr,
+    AtAPc,
+    Reg(u8),
+    AtReg(u8),
+    Imm(u16),
+    /// Direct or bit address, depending on the instruction
+    Addr(u8),
+    /// Bit address given as `byte.bit`
+    Bit(u8),
+    NotBit(u8),
+}
+
+fn parse_number(text: &str) -> Option<u16> {
+    let text = text.to_ascii_uppercase();
+    if let Some(hex) = text.strip_prefix("0X") {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = text.strip_suffix('H') {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn parse_addr(text: &str) -> Option<u8> {
+    sfr_address(text).or_else(|| parse_number(text)?.try_into().ok())
+}
+
+fn parse_bit(text: &str) -> Option<u8> {
+    if let Some((byte, bit)) = text.split_once('.') {
+        let byte = parse_addr(byte)?;
+        let bit = bit.parse::<u8>().ok().filter(|x| *x < 8)?;
+        match byte {
+            0x20..=0x2F => Some((byte - 0x20) * 8 + bit),
+            _ if byte >= 0x80 && byte % 8 == 0 => Some(byte + bit),
+            _ => None,
+        }
+    } else {
+        parse_addr(text)
+    }
+}
+
+fn parse_operand(text: &str) -> Result<Operand> {
+    let upper = text.to_ascii_uppercase();
+    let op = match upper.as_str() {
+        "A" => Operand::A,
+        "AB" => Operand::Ab,
+        "C" => Operand::C,
+        "DPTR" => Operand::Dptr,
+        "@DPTR" => Operand::AtDptr,
+        "@A+DPTR" => Operand::AtADptr,
+        "@A+PC" => Operand::AtAPc,
+        "@R0" | "@R1" => Operand::AtReg(upper.as_bytes()[2] - b'0'),
+        "R0" | "R1" | "R2" | "R3" | "R4" | "R5" | "R6" | "R7" => {
+            Operand::Reg(upper.as_bytes()[1] - b'0')
+        }
+        _ => {
+            let parsed = if let Some(imm) = text.strip_prefix('#') {
+                parse_number(imm).map(Operand::Imm)
+            } else if let Some(bit) = text.strip_prefix('/') {
+                parse_bit(bit).map(Operand::NotBit)
+            } else if text.contains('.') {
+                parse_bit(text).map(Operand::Bit)
+            } else {
+                parse_addr(text).map(Operand::Addr)
+            };
+            parsed.ok_or_else(|| Error::AsmParseError("invalid operand", text.into()))?
+        }
+    };
+    Ok(op)
+}
+
+/// Assembles a single 8051 instruction, e.g. `MOV A, #0x12`
+///
+/// Only instructions that keep the program counter in place are supported.
+/// Numbers can be decimal, `0x12` or `12h`, and known SFRs can be referred to
+/// by name, including bits like `PSW.3`.
+pub fn assemble(text: &str) -> Result<Vec<u8>> {
+    use Operand::*;
+
+    let err = |message| Error::AsmParseError(message, text.into());
+    let text = text.trim();
+    let (mnemonic, operands) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let mnemonic = mnemonic.to_ascii_uppercase();
+    let operands = if operands.trim().is_empty() {
+        vec![]
+    } else {
+        operands
+            .split(',')
+            .map(|x| parse_operand(x.trim()))
+            .collect::<Result<Vec<_>>>()?
+    };
+    let imm8 = |value: u16| u8::try_from(value).map_err(|_| err("immediate value out of range"));
+
+    // Arithmetic and logic instructions sharing the same operand layout
+    let alu_base = match mnemonic.as_str() {
+        "ADD" => Some(0x20),
+        "ADDC" => Some(0x30),
+        "ORL" => Some(0x40),
+        "ANL" => Some(0x50),
+        "XRL" => Some(0x60),
+        "SUBB" => Some(0x90),
+        _ => None,
+    };
+    if let Some(base) = alu_base {
+        match operands[..] {
+            [A, Imm(x)] => return Ok(vec![base + 0x04, imm8(x)?]),
+            [A, Addr(d)] => return Ok(vec![base + 0x05, d]),
+            [A, AtReg(i)] => return Ok(vec![base + 0x06 + i]),
+            [A, Reg(n)] => return Ok(vec![base + 0x08 + n]),
+            [Addr(d), A] if (0x40..=0x60).contains(&base) => return Ok(vec![base + 0x02, d]),
+            [Addr(d), Imm(x)] if (0x40..=0x60).contains(&base) => {
+                return Ok(vec![base + 0x03, d, imm8(x)?]);
+            }
+            _ => {}
+        }
+    }
+
+    let bytes = match (mnemonic.as_str(), &operands[..]) {
+        ("NOP", []) => vec![0x00],
+
+        ("MOV", [A, Imm(x)]) => vec![0x74, imm8(*x)?],
+        ("MOV", [A, Addr(d)]) => vec![0xE5, *d],
+        ("MOV", [A, AtReg(i)]) => vec![0xE6 + i],
+        ("MOV", [A, Reg(n)]) => vec![0xE8 + n],
+        ("MOV", [Reg(n), A]) => vec![0xF8 + n],
+        ("MOV", [Reg(n), Addr(d)]) => vec![0xA8 + n, *d],
+        ("MOV", [Reg(n), Imm(x)]) => vec![0x78 + n, imm8(*x)?],
+        ("MOV", [AtReg(i), A]) => vec![0xF6 + i],
+        ("MOV", [AtReg(i), Addr(d)]) => vec![0xA6 + i, *d],
+        ("MOV", [AtReg(i), Imm(x)]) => vec![0x76 + i, imm8(*x)?],
+        ("MOV", [Addr(d), A]) => vec![0xF5, *d],
+        ("MOV", [Addr(d), Reg(n)]) => vec![0x88 + n, *d],
+        ("MOV", [Addr(d), AtReg(i)]) => vec![0x86 + i, *d],
+        ("MOV", [Addr(d), Addr(s)]) => vec![0x85, *s, *d],
+        ("MOV", [Addr(d), Imm(x)]) => vec![0x75, *d, imm8(*x)?],
+        ("MOV", [C, Addr(b) | Bit(b)]) => vec![0xA2, *b],
+        ("MOV", [Addr(b) | Bit(b), C]) => vec![0x92, *b],
+        ("MOV", [Dptr, Imm(x)]) => [&[0x90][..], &x.to_be_bytes()].concat(),
+        ("MOVC", [A, AtADptr]) => vec![0x93],
+        ("MOVC", [A, AtAPc]) => vec![0x83],
+        ("MOVX", [A, AtDptr]) => vec![0xE0],
+        ("MOVX", [A, AtReg(i)]) => vec![0xE2 + i],
+        ("MOVX", [AtDptr, A]) => vec![0xF0],
+        ("MOVX", [AtReg(i), A]) => vec![0xF2 + i],
+
+        ("ORL", [C, Addr(b) | Bit(b)]) => vec![0x72, *b],
+        ("ANL", [C, Addr(b) | Bit(b)]) => vec![0x82, *b],
+        ("ORL", [C, NotBit(b)]) => vec![0xA0, *b],
+        ("ANL", [C, NotBit(b)]) => vec![0xB0, *b],
+
+        ("INC", [Dptr]) => vec![0xA3],
+        ("INC" | "DEC", [op]) => {
+            let base = if mnemonic == "INC" { 0x00 } else { 0x10 };
+            match op {
+                A => vec![base + 0x04],
+                Addr(d) => vec![base + 0x05, *d],
+                AtReg(i) => vec![base + 0x06 + i],
+                Reg(n) => vec![base + 0x08 + n],
+                _ => return Err(err("unsupported mnemonic or operands")),
+            }
+        }
+        ("MUL", [Ab]) => vec![0xA4],
+        ("DIV", [Ab]) => vec![0x84],
+        ("DA", [A]) => vec![0xD4],
+        ("SWAP", [A]) => vec![0xC4],
+        ("RR", [A]) => vec![0x03],
+        ("RRC", [A]) => vec![0x13],
+        ("RL", [A]) => vec![0x23],
+        ("RLC", [A]) => vec![0x33],
+        ("CLR", [A]) => vec![0xE4],
+        ("CPL", [A]) => vec![0xF4],
+        ("CPL", [C]) => vec![0xB3],
+        ("CLR", [C]) => vec![0xC3],
+        ("SETB", [C]) => vec![0xD3],
+        ("CPL", [Addr(b) | Bit(b)]) => vec![0xB2, *b],
+        ("CLR", [Addr(b) | Bit(b)]) => vec![0xC2, *b],
+        ("SETB", [Addr(b) | Bit(b)]) => vec![0xD2, *b],
+
+        ("XCH", [A, Addr(d)]) => vec![0xC5, *d],
+        ("XCH", [A, AtReg(i)]) => vec![0xC6 + i],
+        ("XCH", [A, Reg(n)]) => vec![0xC8 + n],
+        ("XCHD", [A, AtReg(i)]) => vec![0xD6 + i],
+        ("PUSH", [Addr(d)]) => vec![0xC0, *d],
+        ("POP", [Addr(d)]) => vec![0xD0, *d],
+
+        _ => return Err(err("unsupported mnemonic or operands")),
+    };
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opcode_len() {
+        let lens: Vec<_> = (0..=0xFF).map(|x| opcode_len(x).unwrap_or(0)).collect();
+        assert_eq!(lens.iter().filter(|x| **x == 1).count(), 140);
+        assert_eq!(lens.iter().filter(|x| **x == 2).count(), 91);
+        assert_eq!(lens.iter().filter(|x| **x == 3).count(), 24);
+        assert_eq!(opcode_len(0x75), Some(3));
+        assert_eq!(opcode_len(0x85), Some(3));
+        assert_eq!(opcode_len(0xE5), Some(2));
+        assert_eq!(opcode_len(0xD8), Some(2));
+        assert_eq!(opcode_len(0xA5), None);
+
+        assert!(is_branch(0x02));
+        assert!(is_branch(0x22));
+        assert!(is_branch(0xB5));
+        assert!(is_branch(0x80));
+        assert!(!is_branch(0x90));
+        assert!(!is_branch(0xE5));
+        assert!(!is_branch(0xA2));
+    }
+
+    #[test]
+    fn test_assemble() {
+        assert_eq!(assemble("NOP").unwrap(), [0x00]);
+        assert_eq!(assemble("mov a, #0x12").unwrap(), [0x74, 0x12]);
+        assert_eq!(assemble("MOV A,PSW").unwrap(), [0xE5, 0xD0]);
+        assert_eq!(assemble("MOV 0x30, 0x31").unwrap(), [0x85, 0x31, 0x30]);
+        assert_eq!(assemble("MOV DPTR, #0FFFCh").unwrap(), [0x90, 0xFF, 0xFC]);
+        assert_eq!(assemble("MOV R3, A").unwrap(), [0xFB]);
+        assert_eq!(assemble("MOV @R1, #5").unwrap(), [0x77, 0x05]);
+        assert_eq!(assemble("MOVX A, @DPTR").unwrap(), [0xE0]);
+        assert_eq!(assemble("ORL CKON, #0x01").unwrap(), [0x43, 0x8E, 0x01]);
+        assert_eq!(assemble("ANL C, /P1.2").unwrap(), [0xB0, 0x92]);
+        assert_eq!(assemble("SETB PSW.3").unwrap(), [0xD2, 0xD3]);
+        assert_eq!(assemble("CLR 0x20.7").unwrap(), [0xC2, 0x07]);
+        assert_eq!(assemble("DEC @R0").unwrap(), [0x16]);
+        assert_eq!(assemble("PUSH ACC").unwrap(), [0xC0, 0xE0]);
+
+        for text in [
+            "MOV A",
+            "ADD 0x30, A",
+            "MOV A, #0x100",
+            "SETB P1.8",
+            "LJMP 0",
+        ] {
+            assert!(
+                matches!(assemble(text), Err(Error::AsmParseError(..))),
+                "{text}"
+            );
+        }
+
+        for text in ["INC A", "XCH A, R7", "SUBB A, @R1", "MOV 0x30, #1", "CPL C"] {
+            let bytes = assemble(text).unwrap();
+            assert_eq!(opcode_len(bytes[0]), Some(bytes.len()), "{text}");
+            assert!(!is_branch(bytes[0]), "{text}");
+        }
+    }
+}
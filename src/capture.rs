@@ -0,0 +1,307 @@
+use crate::{
+    error::{Error, Result},
+    transport::Transport,
+};
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{ErrorKind, LineWriter, Write},
+    time::{Duration, Instant},
+};
+
+// Capture files are plain text, one transport call per line:
+//
+//   <microseconds> <request> => <response>
+//
+// For example:
+//
+//   1502 write 55 21 => ok
+//   1530 read 4 => ok 12 62 00 00
+//   1531 read 1 => err io TimedOut
+//   1600 set_reset true => ok
+//   1600 set_timeout 5000 => ok
+
+fn encode_hex(data: &[u8]) -> String {
+    data.iter().fold(String::new(), |mut acc, x| {
+        if !acc.is_empty() {
+            acc.push(' ');
+        }
+        let _ = write!(acc, "{x:02X}");
+        acc
+    })
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    text.split_whitespace()
+        .map(|x| u8::from_str_radix(x, 16).ok())
+        .collect()
+}
+
+fn encode_error(err: &Error) -> String {
+    match err {
+        Error::IOError(err) => format!("io {:?}", err.kind()),
+        Error::WriteReadFailed(err) => format!("write_read_failed {:?}", err.kind()),
+        Error::WriteReadMismatch(index, expected, actual) => {
+            format!("write_read_mismatch {index} {expected:02X} {actual:02X}")
+        }
+        err => format!("other {err}"),
+    }
+}
+
+fn decode_error_kind(text: &str) -> ErrorKind {
+    match text {
+        "TimedOut" => ErrorKind::TimedOut,
+        "UnexpectedEof" => ErrorKind::UnexpectedEof,
+        "BrokenPipe" => ErrorKind::BrokenPipe,
+        "NotConnected" => ErrorKind::NotConnected,
+        "PermissionDenied" => ErrorKind::PermissionDenied,
+        "Unsupported" => ErrorKind::Unsupported,
+        _ => ErrorKind::Other,
+    }
+}
+
+fn decode_error(text: &str) -> Option<Error> {
+    let (kind, detail) = text.split_once(' ').unwrap_or((text, ""));
+    Some(match kind {
+        "io" => Error::IOError(decode_error_kind(detail).into()),
+        "write_read_failed" => Error::WriteReadFailed(decode_error_kind(detail).into()),
+        "write_read_mismatch" => {
+            let mut values = detail.split(' ');
+            let index = values.next()?.parse().ok()?;
+            let expected = u8::from_str_radix(values.next()?, 16).ok()?;
+            let actual = u8::from_str_radix(values.next()?, 16).ok()?;
+            if values.next().is_some() {
+                return None;
+            }
+            Error::WriteReadMismatch(index, expected, actual)
+        }
+        _ => Error::IOError(std::io::Error::other(detail.to_string())),
+    })
+}
+
+/// Transport wrapper that logs every call to a capture file
+pub struct RecordingTransport {
+    inner: Box<dyn Transport>,
+    log: LineWriter<File>,
+    start: Instant,
+}
+
+impl RecordingTransport {
+    pub fn new(inner: Box<dyn Transport>, path: &str) -> Result<Self> {
+        Ok(Self {
+            inner,
+            log: LineWriter::new(File::create(path)?),
+            start: Instant::now(),
+        })
+    }
+
+    fn record<T>(&mut self, request: &str, result: &Result<T>, response: &str) -> Result<()> {
+        let response = match result {
+            Ok(_) if response.is_empty() => "ok".into(),
+            Ok(_) => format!("ok {response}"),
+            Err(err) => format!("err {}", encode_error(err)),
+        };
+        writeln!(
+            self.log,
+            "{} {request} => {response}",
+            self.start.elapsed().as_micros()
+        )?;
+        Ok(())
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        let res = self.inner.write(data);
+        self.record(&format!("write {}", encode_hex(data)), &res, "")?;
+        res
+    }
+
+    fn read(&mut self, data: &mut [u8]) -> Result<()> {
+        let res = self.inner.read(data);
+        self.record(&format!("read {}", data.len()), &res, &encode_hex(data))?;
+        res
+    }
+
+    fn set_reset(&mut self, level: bool) -> Result<()> {
+        let res = self.inner.set_reset(level);
+        self.record(&format!("set_reset {level}"), &res, "")?;
+        res
+    }
+
+    fn set_timeout(&mut self, value: Duration) -> Result<()> {
+        let res = self.inner.set_timeout(value);
+        self.record(&format!("set_timeout {}", value.as_micros()), &res, "")?;
+        res
+    }
+
+    fn timeout(&self) -> Result<Duration> {
+        // Not recorded: replay answers it from the last set_timeout call
+        self.inner.timeout()
+    }
+}
+
+struct ReplayEntry {
+    line: usize,
+    request: String,
+    response: String,
+}
+
+/// Transport that plays a capture file back to the host
+///
+/// Every call is checked against the recording, and the first call that
+/// differs from it fails with [`Error::ReplayMismatch`].
+pub struct ReplayTransport {
+    entries: std::vec::IntoIter<ReplayEntry>,
+    timeout: Duration,
+}
+
+impl ReplayTransport {
+    pub fn open(path: &str) -> Result<Self> {
+        Self::from_capture(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn from_capture(capture: &str) -> Result<Self> {
+        let mut entries = Vec::new();
+        for (i, text) in capture.lines().enumerate() {
+            if text.trim().is_empty() {
+                continue;
+            }
+            let (_, call) = text.split_once(' ').ok_or(Error::ReplayParseError(i + 1))?;
+            let (request, response) = call
+                .split_once(" => ")
+                .ok_or(Error::ReplayParseError(i + 1))?;
+            entries.push(ReplayEntry {
+                line: i + 1,
+                request: request.into(),
+                response: response.into(),
+            });
+        }
+        Ok(Self {
+            entries: entries.into_iter(),
+            timeout: Duration::from_millis(50),
+        })
+    }
+
+    fn replay(&mut self, request: &str) -> Result<String> {
+        let entry = self
+            .entries
+            .next()
+            .ok_or_else(|| Error::ReplayEnded(request.into()))?;
+        if entry.request != request {
+            return Err(Error::ReplayMismatch(
+                entry.line,
+                entry.request,
+                request.into(),
+            ));
+        }
+        if let Some(err) = entry.response.strip_prefix("err ") {
+            return Err(decode_error(err).unwrap_or(Error::ReplayParseError(entry.line)));
+        }
+        match entry.response.strip_prefix("ok") {
+            Some(data) => Ok(data.trim().into()),
+            None => Err(Error::ReplayParseError(entry.line)),
+        }
+    }
+
+    /// Returns `true` if every recorded call has been replayed
+    pub fn is_finished(&self) -> bool {
+        self.entries.len() == 0
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.replay(&format!("write {}", encode_hex(data)))?;
+        Ok(())
+    }
+
+    fn read(&mut self, data: &mut [u8]) -> Result<()> {
+        let line = self.entries.as_slice().first().map(|x| x.line);
+        let response = self.replay(&format!("read {}", data.len()))?;
+        let response = decode_hex(&response)
+            .filter(|x| x.len() == data.len())
+            .ok_or(Error::ReplayParseError(line.unwrap_or_default()))?;
+        data.copy_from_slice(&response);
+        Ok(())
+    }
+
+    fn set_reset(&mut self, level: bool) -> Result<()> {
+        self.replay(&format!("set_reset {level}"))?;
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, value: Duration) -> Result<()> {
+        self.replay(&format!("set_timeout {}", value.as_micros()))?;
+        self.timeout = value;
+        Ok(())
+    }
+
+    fn timeout(&self) -> Result<Duration> {
+        Ok(self.timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{flasher::Flasher, simulator::Simulator};
+
+    #[test]
+    fn test_record_replay() {
+        let path =
+            std::env::temp_dir().join(format!("sn8flash-capture-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let sim = Simulator::new(0x6212, 0x1000, 0x20, 0x100);
+        sim.set_flash(0, &[0x02, 0x00, 0x10]);
+        let session = |transport: Box<dyn Transport>| {
+            let mut flasher = Flasher::new(transport);
+            flasher.set_reset_duration_ms(0);
+            flasher.set_connect_delay_us(0);
+            let chip_id = flasher.connect()?;
+            let mut data = [0; 3];
+            flasher.read_flash(0, &mut data, &|_| {})?;
+            Ok::<_, Error>((chip_id, data))
+        };
+
+        let recorded = session(Box::new(
+            RecordingTransport::new(Box::new(sim), path).unwrap(),
+        ));
+        let replayed = session(Box::new(ReplayTransport::open(path).unwrap()));
+        assert_eq!(recorded.unwrap(), (0x6212, [0x02, 0x00, 0x10]));
+        assert_eq!(replayed.unwrap(), (0x6212, [0x02, 0x00, 0x10]));
+
+        let mut flasher = Flasher::new(Box::new(ReplayTransport::open(path).unwrap()));
+        flasher.set_final_reset(false);
+        flasher.set_reset_duration_ms(0);
+        flasher.set_connect_delay_us(0);
+        flasher.connect().unwrap();
+        assert!(matches!(
+            flasher.erase_flash(),
+            Err(Error::ReplayMismatch(..))
+        ));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_error_encoding() {
+        let err = Error::WriteReadMismatch(3, 0x55, 0x54);
+        assert_eq!(encode_error(&err), "write_read_mismatch 3 55 54");
+        assert!(matches!(
+            decode_error(&encode_error(&err)),
+            Some(Error::WriteReadMismatch(3, 0x55, 0x54))
+        ));
+        assert!(decode_error("write_read_mismatch").is_none());
+        assert!(decode_error("write_read_mismatch 3 55").is_none());
+        assert!(decode_error("write_read_mismatch 3 55 5G").is_none());
+
+        let mut replay =
+            ReplayTransport::from_capture("0 write 55 => err write_read_mismatch\n").unwrap();
+        assert!(matches!(
+            replay.write(&[0x55]),
+            Err(Error::ReplayParseError(1))
+        ));
+    }
+}
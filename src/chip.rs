@@ -1,191 +1,46 @@
-use std::fmt::Display;
+use crate::error::{Error, Result};
+use std::{fmt::Display, sync::LazyLock};
 
-#[derive(gset::Getset, PartialEq, Eq, Debug, Clone, Copy)]
+#[derive(gset::Getset, serde::Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct ChipInfo {
+    #[getset(get_deref, vis = "pub")]
+    series: String,
     #[getset(get_copy, vis = "pub")]
-    series: &'static str,
-    #[getset(get_copy, vis = "pub")]
+    #[getset(set, vis = "pub")]
     flash_size: u32,
     #[getset(get_copy, vis = "pub")]
     page_size: u8,
+    #[getset(get_copy, vis = "pub")]
+    erased_value: u8,
+    /// Internal RAM size in bytes, if known
+    #[serde(default)]
+    #[getset(get_copy, vis = "pub")]
+    ram_size: Option<u16>,
+    /// External RAM size in bytes, if known
+    #[serde(default)]
+    #[getset(get_copy, vis = "pub")]
+    xram_size: Option<u16>,
+    /// Boot parameter area size in bytes, if known
+    #[serde(default)]
+    #[getset(get_copy, vis = "pub")]
+    boot_size: Option<u16>,
+    /// Whether the chip has the CKON clock control register, if known
+    #[serde(default)]
+    #[getset(get_copy, vis = "pub")]
+    has_ckon: Option<bool>,
+    /// Unique device ID location in boot parameter area, if documented
+    #[serde(default)]
+    #[getset(get_copy, vis = "pub")]
+    uid_offset: Option<u16>,
+    #[serde(default)]
+    #[getset(get_copy, vis = "pub")]
+    uid_len: Option<u8>,
 }
 
 impl ChipInfo {
+    /// Looks the chip up in the built-in database
     pub fn from_chip_id(chip_id: u32) -> Option<Self> {
-        // These values are hand-copied from "SNLINK_C51.INI" of official Keil plug-in
-        match chip_id {
-            0x1110..0x1120 => Some(Self {
-                series: "SNPD5111",
-                flash_size: 0x4000,
-                page_size: 0x20,
-            }),
-            0x2710..0x2720 => Some(Self {
-                series: "SN8F5283",
-                flash_size: 0x4000,
-                page_size: 0x20,
-            }),
-            0x6100..0x6110 => Some(Self {
-                series: "SN8F5701",
-                flash_size: 0x1000,
-                page_size: 0x20,
-            }),
-            0x6110..0x6120 => Some(Self {
-                series: "SN8F5721",
-                flash_size: 0x1000,
-                page_size: 0x20,
-            }),
-            0x6200..0x6216 => Some(Self {
-                series: "SN8F5702",
-                flash_size: 0x1000,
-                page_size: 0x20,
-            }),
-            0x6216..0x6220 => Some(Self {
-                series: "SN8F5702A",
-                flash_size: 0x1000,
-                page_size: 0x20,
-            }),
-            0x6220..0x6230 => Some(Self {
-                series: "SN8F5732",
-                flash_size: 0x4000,
-                page_size: 0x20,
-            }),
-            0x6240..0x6250 => Some(Self {
-                series: "SN8F5762",
-                flash_size: 0x4800,
-                page_size: 0x40,
-            }),
-            0x6260..0x6270 => Some(Self {
-                series: "SN8F5782",
-                flash_size: 0x10000,
-                page_size: 0x40,
-            }),
-            0x6270..0x6280 => Some(Self {
-                series: "SN8F5602",
-                flash_size: 0x4800,
-                page_size: 0x40,
-            }),
-            0x6300..0x6310 => Some(Self {
-                series: "SN8F5703",
-                flash_size: 0x2000,
-                page_size: 0x20,
-            }),
-            0x6310..0x6330 => Some(Self {
-                series: "SN8F5713",
-                flash_size: 0x2000,
-                page_size: 0x20,
-            }),
-            0x6330..0x6336 => Some(Self {
-                series: "SN8F5703",
-                flash_size: 0x2000,
-                page_size: 0x20,
-            }),
-            0x6336..0x6340 => Some(Self {
-                series: "SN8F5703A",
-                flash_size: 0x2000,
-                page_size: 0x20,
-            }),
-            0x6400..0x6410 => Some(Self {
-                series: "SN8F5754",
-                flash_size: 0x4000,
-                page_size: 0x20,
-            }),
-            0x6700..0x6720 => Some(Self {
-                series: "SN8F5708",
-                flash_size: 0x4000,
-                page_size: 0x20,
-            }),
-            0x8401..0x8410 => Some(Self {
-                series: "SN8F5804",
-                flash_size: 0x2000,
-                page_size: 0x20,
-            }),
-            0x8410..0x8420 => Some(Self {
-                series: "SN8F5814",
-                flash_size: 0x4000,
-                page_size: 0x20,
-            }),
-            0x8420..0x8430 => Some(Self {
-                series: "SN8F5804A",
-                flash_size: 0x2000,
-                page_size: 0x20,
-            }),
-            0x8500..0x8510 => Some(Self {
-                series: "SN8F5835",
-                flash_size: 0x8000,
-                page_size: 0x40,
-            }),
-            0x8700..0x8710 => Some(Self {
-                series: "SN8F5858",
-                flash_size: 0x4000,
-                page_size: 0x20,
-            }),
-            0x8800..0x8820 => Some(Self {
-                series: "SN8F5829",
-                flash_size: 0x8000,
-                page_size: 0x40,
-            }),
-            0x8820..0x8830 => Some(Self {
-                series: "SN8F5840",
-                flash_size: 0x08000,
-                page_size: 0x40,
-            }),
-            0x8830..0x8840 => Some(Self {
-                series: "SN8F5869",
-                flash_size: 0x10000,
-                page_size: 0x40,
-            }),
-            0x9901..0x9910 => Some(Self {
-                series: "SN8F5900",
-                flash_size: 0x10000,
-                page_size: 0x40,
-            }),
-            0x9910..0x9920 => Some(Self {
-                series: "SN8F5910",
-                flash_size: 0x08000,
-                page_size: 0x40,
-            }),
-            0x9920..0x9930 => Some(Self {
-                series: "SN8F5900A",
-                flash_size: 0x10000,
-                page_size: 0x40,
-            }),
-            0x9930..0x9940 => Some(Self {
-                series: "SN8F5930",
-                flash_size: 0x20000,
-                page_size: 0x40,
-            }),
-            0x9940..0x9950 => Some(Self {
-                series: "SN8F5920",
-                flash_size: 0x08000,
-                page_size: 0x40,
-            }),
-            0x9950..0x9960 => Some(Self {
-                series: "SN8F5950",
-                flash_size: 0x20000,
-                page_size: 0x40,
-            }),
-            0x9960..0x9970 => Some(Self {
-                series: "SN8F5960",
-                flash_size: 0x10000,
-                page_size: 0x40,
-            }),
-            0x99A0..0x99B0 => Some(Self {
-                series: "SN8F5900B",
-                flash_size: 0x10000,
-                page_size: 0x40,
-            }),
-            0x99B0..0x99C0 => Some(Self {
-                series: "SN8F5940",
-                flash_size: 0x20000,
-                page_size: 0x40,
-            }),
-            0x99C0..0x99D0 => Some(Self {
-                series: "SN8F5900C",
-                flash_size: 0x10000,
-                page_size: 0x40,
-            }),
-            _ => None,
-        }
+        ChipDb::builtin().find(chip_id).cloned()
     }
 }
 
@@ -200,3 +55,110 @@ impl Display for ChipInfo {
         )
     }
 }
+
+/// Chip database entry, matching chip IDs in `id_start..id_end`
+#[derive(gset::Getset, serde::Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct ChipDbEntry {
+    #[getset(get_copy, vis = "pub")]
+    id_start: u32,
+    #[getset(get_copy, vis = "pub")]
+    id_end: u32,
+    #[serde(flatten)]
+    #[getset(get, vis = "pub")]
+    info: ChipInfo,
+}
+
+#[derive(gset::Getset, serde::Deserialize, Default, Debug, Clone)]
+pub struct ChipDb {
+    #[serde(default, rename = "chip")]
+    #[getset(get_deref, vis = "pub")]
+    entries: Vec<ChipDbEntry>,
+}
+
+static BUILTIN_CHIP_DB: LazyLock<ChipDb> = LazyLock::new(|| {
+    ChipDb::from_toml(include_str!("chips.toml")).expect("built-in chip database is valid")
+});
+
+impl ChipDb {
+    /// Built-in database, see `chips.toml`
+    pub fn builtin() -> &'static Self {
+        &BUILTIN_CHIP_DB
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self> {
+        toml::from_str(text).map_err(Error::ChipDbParseError)
+    }
+
+    pub fn from_file(path: &str) -> Result<Self> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    /// Adds entries of another database, taking precedence over existing ones
+    pub fn extend(&mut self, other: Self) {
+        self.entries.splice(0..0, other.entries);
+    }
+
+    pub fn find(&self, chip_id: u32) -> Option<&ChipInfo> {
+        self.entries
+            .iter()
+            .find(|x| (x.id_start..x.id_end).contains(&chip_id))
+            .map(|x| &x.info)
+    }
+
+    /// Looks a chip up by series name, ignoring case
+    pub fn find_series(&self, series: &str) -> Option<&ChipInfo> {
+        self.entries
+            .iter()
+            .map(|x| &x.info)
+            .find(|x| x.series.eq_ignore_ascii_case(series))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chip_db() {
+        let db = ChipDb::builtin();
+        assert_eq!(db.entries().len(), 34);
+        assert_eq!(db.find(0x6212).unwrap().series(), "SN8F5702");
+        assert_eq!(db.find(0x6101).unwrap().erased_value(), 0x00);
+        assert_eq!(db.find(0x6101).unwrap().boot_size(), None);
+        assert_eq!(db.find(0x6101).unwrap().has_ckon(), None);
+        assert_eq!(db.find(0x6230), None);
+        assert_eq!(db.find_series("sn8f5703a").unwrap().flash_size(), 0x2000);
+        assert_eq!(db.find_series("SN8F5703B"), None);
+
+        let mut db = db.clone();
+        db.extend(
+            ChipDb::from_toml(
+                r#"
+                [[chip]]
+                series = "SN8F5702X"
+                id_start = 0x6210
+                id_end = 0x6214
+                flash_size = 0x1000
+                page_size = 0x20
+                erased_value = 0xFF
+                xram_size = 0x200
+                has_ckon = false
+                uid_offset = 0x80
+                uid_len = 8
+                "#,
+            )
+            .unwrap(),
+        );
+        assert_eq!(db.find(0x6212).unwrap().series(), "SN8F5702X");
+        assert_eq!(db.find(0x6212).unwrap().xram_size(), Some(0x200));
+        assert_eq!(db.find(0x6212).unwrap().has_ckon(), Some(false));
+        assert_eq!(db.find(0x6212).unwrap().uid_offset(), Some(0x80));
+        assert_eq!(db.find(0x6214).unwrap().uid_offset(), None);
+        assert_eq!(db.find(0x6214).unwrap().series(), "SN8F5702");
+
+        assert!(matches!(
+            ChipDb::from_toml("[[chip]]\nseries = \"X\""),
+            Err(Error::ChipDbParseError(_))
+        ));
+    }
+}
@@ -1,3 +1,6 @@
+use crate::verify::VerifyReport;
+use std::ops::Range;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(thiserror::Error, Debug)]
@@ -8,12 +11,17 @@ pub enum Error {
     #[error("Failed to read written data from serial port, check RX+TX connection")]
     WriteReadFailed(#[source] std::io::Error),
 
-    #[error("Write/read data mismatch, check RX+TX connection")]
-    WriteReadMismatch,
+    #[error(
+        "Write/read data mismatch at byte {0}: sent {1:#04X}, received {2:#04X}, check RX+TX connection"
+    )]
+    WriteReadMismatch(usize, u8, u8),
 
     #[error("No handshake response, check reset circuit and chip connection")]
     HandshakeResponseTimeout,
 
+    #[error("Reset command {0} failed with {1}")]
+    ResetCommandFailed(String, std::process::ExitStatus),
+
     #[error("Invalid handshake response {0:X?}")]
     HandshakeResponseMismatch([u8; 4]),
 
@@ -26,12 +34,100 @@ pub enum Error {
     #[error("Erasing a non-main ROM bank is not allowed")]
     NonMainBankErase,
 
-    #[error("Verify mismatch at offsets {0:X?}")]
-    VerifyMismatch(Vec<usize>),
+    #[error(
+        "Page erase uses an undocumented ISP command not verified on hardware, it must be allowed explicitly"
+    )]
+    UnverifiedPageErase,
+
+    #[error("Page erase is never allowed on a non-main ROM bank")]
+    NonMainBankPageErase,
+
+    #[error("Firmware does not fit into {0:#X} bytes of flash, out of bounds at {ranges}", ranges = format_ranges(.1))]
+    FirmwareOutOfBounds(usize, Vec<Range<usize>>),
+
+    #[error("{0} range of {2} bytes at {1:#X} is out of bounds")]
+    AddressOutOfRange(&'static str, usize, usize),
+
+    #[error("Invalid instruction {0:02X?}: {1}")]
+    InvalidInstruction(Vec<u8>, &'static str),
+
+    #[error("Failed to assemble \"{1}\": {0}")]
+    AsmParseError(&'static str, String),
+
+    #[error("Malformed GDB packet")]
+    GdbPacketError,
+
+    #[error("Verify mismatch: {0}")]
+    VerifyMismatch(VerifyReport),
 
     #[error("Intel HEX data is not valid UTF-8")]
     IHexDecodeError(#[source] std::str::Utf8Error),
 
     #[error("Intel HEX parse error on line {1}")]
     IHexParseError(#[source] ihex::ReaderError, usize),
+
+    #[error("S-record data is not valid UTF-8")]
+    SRecordDecodeError(#[source] std::str::Utf8Error),
+
+    #[error("S-record parse error on line {1}")]
+    SRecordParseError(#[source] SRecordError, usize),
+
+    #[error("ELF parse error: {0}")]
+    ElfParseError(&'static str),
+
+    #[error("OMF-51 parse error at byte {1}: {0}")]
+    OmfParseError(&'static str, usize),
+
+    #[error("Intel HEX encode error")]
+    IHexEncodeError(#[source] ihex::WriterError),
+
+    #[error("Serial number {0} does not fit into {1} bytes")]
+    SerialNumberTooWide(u64, usize),
+
+    #[error("Serial number {0} is the last one that fits into {1} bytes")]
+    SerialNumberExhausted(u64, usize),
+
+    #[error("Invalid chip database")]
+    ChipDbParseError(#[source] toml::de::Error),
+
+    #[error("Invalid boot backup file: {0}")]
+    BootBackupParseError(&'static str),
+
+    #[error("Boot backup was taken from chip ID {0:#X}, but this chip ID is {1:#X}")]
+    BootBackupChipMismatch(u32, u32),
+
+    #[error("Invalid capture file on line {0}")]
+    ReplayParseError(usize),
+
+    #[error("Replay diverged on line {0}: recorded \"{1}\", host sent \"{2}\"")]
+    ReplayMismatch(usize, String, String),
+
+    #[error("Replay ended, host sent \"{0}\"")]
+    ReplayEnded(String),
+}
+
+fn format_ranges(ranges: &[Range<usize>]) -> String {
+    ranges
+        .iter()
+        .map(|x| format!("{:#06X}..{:#06X}", x.start, x.end))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SRecordError {
+    #[error("Record does not start with 'S'")]
+    MissingStart,
+
+    #[error("Invalid record type S{0}")]
+    InvalidType(char),
+
+    #[error("Invalid hex digits")]
+    InvalidHex,
+
+    #[error("Byte count does not match record length")]
+    LengthMismatch,
+
+    #[error("Checksum mismatch")]
+    ChecksumMismatch,
 }
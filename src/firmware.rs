@@ -1,6 +1,18 @@
-use crate::error::{Error, Result};
+use crate::error::{Error, Result, SRecordError};
 use std::{cmp::max, ffi::OsStr, path::Path};
 
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELF_PT_LOAD: u64 = 1;
+
+const OMF_MODULE_HEADER: u8 = 0x02;
+const OMF_CONTENT: u8 = 0x06;
+
+/// Erased runs shorter than this are kept when splitting data into sections
+const MIN_ERASED_RUN: usize = 16;
+
+/// Data bytes per line in Intel HEX and S-record output
+const RECORD_LEN: usize = 16;
+
 #[derive(gset::Getset, PartialEq, Eq, Debug)]
 pub struct Section {
     #[getset(get_copy, vis = "pub")]
@@ -10,6 +22,29 @@ pub struct Section {
 }
 
 impl Section {
+    pub fn new(offset: usize, data: Vec<u8>) -> Self {
+        Self { offset, data }
+    }
+
+    /// Splits raw data into sections, leaving out long runs of erased bytes
+    pub fn split_erased(offset: usize, data: &[u8], erased_value: u8) -> Vec<Self> {
+        let mut sections: Vec<Self> = Vec::new();
+        let mut last_end = 0;
+        for (i, byte) in data.iter().enumerate() {
+            if *byte == erased_value {
+                continue;
+            }
+            match sections.last_mut() {
+                Some(section) if i - last_end < MIN_ERASED_RUN => {
+                    section.data.extend(&data[last_end..=i]);
+                }
+                _ => sections.push(Self::new(offset + i, vec![*byte])),
+            }
+            last_end = i + 1;
+        }
+        sections
+    }
+
     pub fn len(&self) -> usize {
         self.data.len()
     }
@@ -21,6 +56,126 @@ impl Section {
     pub fn end(&self) -> usize {
         self.offset + self.len()
     }
+
+    pub fn contains(&self, address: usize) -> bool {
+        (self.offset..self.end()).contains(&address)
+    }
+}
+
+/// Boot parameter area contents together with the chip ID they belong to
+///
+/// Stored as `SN8FBOOT` magic, then chip ID and data length as
+/// little-endian `u32`s, then the data.
+#[derive(gset::Getset, PartialEq, Eq, Debug)]
+pub struct BootBackup {
+    #[getset(get_copy, vis = "pub")]
+    chip_id: u32,
+    #[getset(get_deref, vis = "pub")]
+    data: Vec<u8>,
+}
+
+impl BootBackup {
+    const MAGIC: [u8; 8] = *b"SN8FBOOT";
+    const HEADER_LEN: usize = 16;
+
+    pub fn new(chip_id: u32, data: Vec<u8>) -> Self {
+        Self { chip_id, data }
+    }
+
+    pub fn from_bytes(raw: &[u8]) -> Result<Self> {
+        let header = raw
+            .get(..Self::HEADER_LEN)
+            .ok_or(Error::BootBackupParseError("file is too short"))?;
+        if header[..8] != Self::MAGIC {
+            return Err(Error::BootBackupParseError("missing SN8FBOOT magic"));
+        }
+        let chip_id = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        let len = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+        if raw.len() != Self::HEADER_LEN + len {
+            return Err(Error::BootBackupParseError("data length mismatch"));
+        }
+        Ok(Self::new(chip_id, raw[Self::HEADER_LEN..].to_vec()))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(Self::HEADER_LEN + self.data.len());
+        raw.extend(Self::MAGIC);
+        raw.extend(self.chip_id.to_le_bytes());
+        raw.extend((self.data.len() as u32).to_le_bytes());
+        raw.extend(&self.data);
+        raw
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        std::fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FirmwareFormat {
+    Raw,
+    IntelHex,
+    SRecord,
+    Elf,
+    /// Absolute Intel/Keil OMF-51 object
+    Omf,
+}
+
+impl FirmwareFormat {
+    /// Guesses the format from the file extension
+    pub fn from_path(path: &str) -> Option<Self> {
+        let extension = Path::new(path).extension().and_then(OsStr::to_str)?;
+        match extension.to_ascii_lowercase().as_str() {
+            "bin" => Some(Self::Raw),
+            "hex" | "ihex" | "ihx" => Some(Self::IntelHex),
+            "s19" | "s28" | "s37" | "srec" | "mot" => Some(Self::SRecord),
+            "elf" => Some(Self::Elf),
+            "omf" | "abs" | "aomf" => Some(Self::Omf),
+            _ => None,
+        }
+    }
+
+    /// Recognizes the format by file contents, raw binary is never detected
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(&ELF_MAGIC) {
+            return Some(Self::Elf);
+        }
+        if Firmware::is_omf(data) {
+            return Some(Self::Omf);
+        }
+        // Text formats: check that the first line is a well-formed record
+        let text = data.trim_ascii_start();
+        let line = text
+            .split(|x| *x == b'\n')
+            .next()
+            .unwrap_or_default()
+            .trim_ascii_end();
+        let is_hex = |x: &[u8]| !x.is_empty() && x.iter().all(u8::is_ascii_hexdigit);
+        match line {
+            [b':', rest @ ..] if is_hex(rest) && rest.len() % 2 == 0 => Some(Self::IntelHex),
+            [b'S', b'0'..=b'9', rest @ ..] if is_hex(rest) && rest.len() % 2 == 0 => {
+                Some(Self::SRecord)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for FirmwareFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Raw => "raw binary",
+            Self::IntelHex => "Intel HEX",
+            Self::SRecord => "Motorola S-record",
+            Self::Elf => "ELF",
+            Self::Omf => "OMF-51",
+        })
+    }
 }
 
 #[derive(gset::Getset, Debug)]
@@ -34,25 +189,62 @@ pub struct Firmware {
 }
 
 impl Firmware {
-    pub fn from_file(path: &str, page_size: usize, base_offset: usize) -> Result<Self> {
+    /// Loads firmware from a file
+    ///
+    /// Without an explicit `format` the file contents are sniffed first,
+    /// then the extension is used, and raw binary is the fallback.
+    pub fn from_file(
+        path: &str,
+        format: Option<FirmwareFormat>,
+        page_size: usize,
+        base_offset: usize,
+        filler: u8,
+    ) -> Result<Self> {
         let data = std::fs::read(path)?;
-        let extension = Path::new(path)
-            .extension()
-            .and_then(OsStr::to_str)
-            .unwrap_or_default();
-        match extension {
-            "hex" | "ihex" | "ihx" => {
-                log::info!("Loading {path} as Intel HEX");
-                Self::from_intel_hex(data, page_size, base_offset)
-            }
-            _ => {
-                log::info!("Loading {path} as raw binary");
-                Self::from_raw_bytes(data, page_size, base_offset)
+        let format = format.unwrap_or_else(|| {
+            let by_extension = FirmwareFormat::from_path(path);
+            let by_content = FirmwareFormat::detect(&data);
+            match (by_extension, by_content) {
+                (Some(extension), Some(content)) if extension != content => {
+                    log::warn!(
+                        "{path} looks like {content} but has {extension} extension, loading as {content}"
+                    );
+                }
+                (Some(extension), None) if extension != FirmwareFormat::Raw => {
+                    log::warn!(
+                        "{path} has {extension} extension but its contents are not recognized"
+                    );
+                }
+                _ => {}
             }
+            by_content.or(by_extension).unwrap_or(FirmwareFormat::Raw)
+        });
+        log::info!("Loading {path} as {format}");
+        Self::from_bytes(data, format, page_size, base_offset, filler)
+    }
+
+    pub fn from_bytes(
+        data: Vec<u8>,
+        format: FirmwareFormat,
+        page_size: usize,
+        base_offset: usize,
+        filler: u8,
+    ) -> Result<Self> {
+        match format {
+            FirmwareFormat::Raw => Self::from_raw_bytes(data, page_size, base_offset, filler),
+            FirmwareFormat::IntelHex => Self::from_intel_hex(data, page_size, base_offset, filler),
+            FirmwareFormat::SRecord => Self::from_srecord(data, page_size, base_offset, filler),
+            FirmwareFormat::Elf => Self::from_elf(data, page_size, base_offset, filler),
+            FirmwareFormat::Omf => Self::from_omf(data, page_size, base_offset, filler),
         }
     }
 
-    pub fn from_raw_bytes(raw: Vec<u8>, page_size: usize, base_offset: usize) -> Result<Self> {
+    pub fn from_raw_bytes(
+        raw: Vec<u8>,
+        page_size: usize,
+        base_offset: usize,
+        filler: u8,
+    ) -> Result<Self> {
         let unaligned_sections = vec![Section {
             offset: base_offset,
             data: raw,
@@ -63,7 +255,7 @@ impl Firmware {
                 Self::sections_len(&unaligned_sections)
             );
         }
-        let sections = Self::align_and_merge_sections(unaligned_sections, page_size);
+        let sections = Self::align_and_merge_sections(unaligned_sections, page_size, filler);
         Ok(Self {
             len: Self::sections_len(&sections),
             page_size,
@@ -71,7 +263,12 @@ impl Firmware {
         })
     }
 
-    pub fn from_intel_hex(raw: Vec<u8>, page_size: usize, base_offset: usize) -> Result<Self> {
+    pub fn from_intel_hex(
+        raw: Vec<u8>,
+        page_size: usize,
+        base_offset: usize,
+        filler: u8,
+    ) -> Result<Self> {
         let mut hex_offset = 0;
         let mut unaligned_sections = Vec::new();
         let hex_str = std::str::from_utf8(&raw).map_err(Error::IHexDecodeError)?;
@@ -102,7 +299,247 @@ impl Firmware {
                 Self::sections_len(&unaligned_sections)
             );
         }
-        let sections = Self::align_and_merge_sections(unaligned_sections, page_size);
+        let sections = Self::align_and_merge_sections(unaligned_sections, page_size, filler);
+        Ok(Self {
+            len: Self::sections_len(&sections),
+            page_size,
+            sections,
+        })
+    }
+
+    pub fn from_srecord(
+        raw: Vec<u8>,
+        page_size: usize,
+        base_offset: usize,
+        filler: u8,
+    ) -> Result<Self> {
+        let mut unaligned_sections = Vec::new();
+        let srec_str = std::str::from_utf8(&raw).map_err(Error::SRecordDecodeError)?;
+        for (i, line) in srec_str.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            match Self::parse_srecord(line) {
+                Ok(Some(section)) => unaligned_sections.push(Section {
+                    offset: base_offset + section.offset,
+                    data: section.data,
+                }),
+                Ok(None) => {}
+                Err(err) => return Err(Error::SRecordParseError(err, i + 1)),
+            }
+        }
+        if log::log_enabled!(log::Level::Debug) {
+            log::debug!(
+                "S-record contains {} bytes",
+                Self::sections_len(&unaligned_sections)
+            );
+        }
+        let sections = Self::align_and_merge_sections(unaligned_sections, page_size, filler);
+        Ok(Self {
+            len: Self::sections_len(&sections),
+            page_size,
+            sections,
+        })
+    }
+
+    fn parse_srecord(line: &str) -> std::result::Result<Option<Section>, SRecordError> {
+        let body = line.strip_prefix('S').ok_or(SRecordError::MissingStart)?;
+        let record_type = body.chars().next().ok_or(SRecordError::MissingStart)?;
+        let hex = &body[record_type.len_utf8()..];
+        if hex.len() % 2 != 0 || !hex.is_ascii() {
+            return Err(SRecordError::InvalidHex);
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_err(|_| SRecordError::InvalidHex)?;
+        let (count, rest) = bytes.split_first().ok_or(SRecordError::LengthMismatch)?;
+        if *count as usize != rest.len() || rest.is_empty() {
+            return Err(SRecordError::LengthMismatch);
+        }
+        if bytes.iter().fold(0u8, |acc, x| acc.wrapping_add(*x)) != 0xFF {
+            return Err(SRecordError::ChecksumMismatch);
+        }
+        let address_len = match record_type {
+            '1' => 2,
+            '2' => 3,
+            '3' => 4,
+            '0' | '5' | '6' | '7' | '8' | '9' => return Ok(None),
+            other => return Err(SRecordError::InvalidType(other)),
+        };
+        let payload = &rest[..rest.len() - 1];
+        if payload.len() < address_len {
+            return Err(SRecordError::LengthMismatch);
+        }
+        let (address, data) = payload.split_at(address_len);
+        let offset = address.iter().fold(0, |acc, x| (acc << 8) | *x as usize);
+        Ok(Some(Section {
+            offset,
+            data: data.to_vec(),
+        }))
+    }
+
+    /// Loads PT_LOAD segments of an ELF file at their physical addresses
+    pub fn from_elf(
+        raw: Vec<u8>,
+        page_size: usize,
+        base_offset: usize,
+        filler: u8,
+    ) -> Result<Self> {
+        if !raw.starts_with(&ELF_MAGIC) {
+            return Err(Error::ElfParseError("missing ELF magic"));
+        }
+        let is_64 = match raw.get(4) {
+            Some(1) => false,
+            Some(2) => true,
+            _ => return Err(Error::ElfParseError("invalid ELF class")),
+        };
+        let is_be = match raw.get(5) {
+            Some(1) => false,
+            Some(2) => true,
+            _ => return Err(Error::ElfParseError("invalid ELF data encoding")),
+        };
+        let read = |offset: usize, len: usize| -> Result<u64> {
+            let bytes = offset
+                .checked_add(len)
+                .and_then(|end| raw.get(offset..end))
+                .ok_or(Error::ElfParseError("unexpected end of file"))?;
+            let fold = |acc: u64, x: &u8| (acc << 8) | *x as u64;
+            Ok(if is_be {
+                bytes.iter().fold(0, fold)
+            } else {
+                bytes.iter().rev().fold(0, fold)
+            })
+        };
+
+        let (ph_offset, ph_entry_size, ph_count) = if is_64 {
+            (read(0x20, 8)?, read(0x36, 2)?, read(0x38, 2)?)
+        } else {
+            (read(0x1C, 4)?, read(0x2A, 2)?, read(0x2C, 2)?)
+        };
+
+        let mut unaligned_sections = Vec::new();
+        for i in 0..ph_count {
+            let header = i
+                .checked_mul(ph_entry_size)
+                .and_then(|x| x.checked_add(ph_offset))
+                .and_then(|x| usize::try_from(x).ok())
+                .filter(|&x| x < raw.len())
+                .ok_or(Error::ElfParseError("program header out of file bounds"))?;
+            let (p_type, p_offset, p_paddr, p_filesz) = if is_64 {
+                (
+                    read(header, 4)?,
+                    read(header + 0x08, 8)?,
+                    read(header + 0x18, 8)?,
+                    read(header + 0x20, 8)?,
+                )
+            } else {
+                (
+                    read(header, 4)?,
+                    read(header + 0x04, 4)?,
+                    read(header + 0x0C, 4)?,
+                    read(header + 0x10, 4)?,
+                )
+            };
+            if p_type != ELF_PT_LOAD || p_filesz == 0 {
+                continue;
+            }
+            let data = p_offset
+                .checked_add(p_filesz)
+                .and_then(|end| Some(usize::try_from(p_offset).ok()?..usize::try_from(end).ok()?))
+                .and_then(|range| raw.get(range))
+                .ok_or(Error::ElfParseError("segment data out of file bounds"))?;
+            let offset = usize::try_from(p_paddr)
+                .ok()
+                .and_then(|x| x.checked_add(base_offset))
+                .ok_or(Error::ElfParseError("segment address out of range"))?;
+            log::debug!("ELF segment at {p_paddr:#X}, {p_filesz} bytes");
+            unaligned_sections.push(Section {
+                offset,
+                data: data.to_vec(),
+            });
+        }
+        if log::log_enabled!(log::Level::Debug) {
+            log::debug!(
+                "ELF contains {} bytes",
+                Self::sections_len(&unaligned_sections)
+            );
+        }
+        let sections = Self::align_and_merge_sections(unaligned_sections, page_size, filler);
+        Ok(Self {
+            len: Self::sections_len(&sections),
+            page_size,
+            sections,
+        })
+    }
+
+    fn is_omf(raw: &[u8]) -> bool {
+        // Module header: name length, name, translator ID, reserved byte.
+        // Raw 8051 images start with 0x02 (LJMP) too, so check all of it.
+        let Some([record_type, len_low, len_high, name_len]) = raw.get(..4) else {
+            return false;
+        };
+        let len = u16::from_le_bytes([*len_low, *len_high]) as usize;
+        let name_len = *name_len as usize;
+        *record_type == OMF_MODULE_HEADER
+            && len == name_len + 4
+            && raw.get(..3 + len).is_some_and(|x| {
+                x[4..4 + name_len].iter().all(u8::is_ascii_graphic)
+                    && x.iter().fold(0u8, |acc, x| acc.wrapping_add(*x)) == 0
+            })
+    }
+
+    /// Loads content records of an absolute Intel/Keil OMF-51 object file
+    pub fn from_omf(
+        raw: Vec<u8>,
+        page_size: usize,
+        base_offset: usize,
+        filler: u8,
+    ) -> Result<Self> {
+        let mut unaligned_sections = Vec::new();
+        let mut pos = 0;
+        while pos < raw.len() {
+            let header = raw
+                .get(pos..pos + 3)
+                .ok_or(Error::OmfParseError("truncated record header", pos))?;
+            let end = pos + 3 + u16::from_le_bytes([header[1], header[2]]) as usize;
+            let record = raw
+                .get(pos..end)
+                .ok_or(Error::OmfParseError("truncated record", pos))?;
+            if record.iter().fold(0u8, |acc, x| acc.wrapping_add(*x)) != 0 {
+                return Err(Error::OmfParseError("checksum mismatch", pos));
+            }
+            if pos == 0 && record[0] != OMF_MODULE_HEADER {
+                return Err(Error::OmfParseError("missing module header", pos));
+            }
+            if record[0] == OMF_CONTENT {
+                // Segment ID, offset, data and checksum
+                let [segment, offset_low, offset_high, ref data @ .., _] = record[3..] else {
+                    return Err(Error::OmfParseError("content record too short", pos));
+                };
+                if segment != 0 {
+                    return Err(Error::OmfParseError(
+                        "relocatable content record, link the module first",
+                        pos,
+                    ));
+                }
+                let offset = u16::from_le_bytes([offset_low, offset_high]) as usize;
+                unaligned_sections.push(Section {
+                    offset: base_offset + offset,
+                    data: data.to_vec(),
+                });
+            }
+            pos = end;
+        }
+        if log::log_enabled!(log::Level::Debug) {
+            log::debug!(
+                "OMF-51 contains {} bytes",
+                Self::sections_len(&unaligned_sections)
+            );
+        }
+        let sections = Self::align_and_merge_sections(unaligned_sections, page_size, filler);
         Ok(Self {
             len: Self::sections_len(&sections),
             page_size,
@@ -110,9 +547,54 @@ impl Firmware {
         })
     }
 
-    fn align_and_merge_sections(mut sections: Vec<Section>, page_size: usize) -> Vec<Section> {
+    /// Checks that all sections fit into flash, since writes past the end
+    /// wrap around to the start
+    pub fn check_bounds(&self, flash_size: usize) -> Result<()> {
+        let out_of_bounds: Vec<_> = self
+            .sections
+            .iter()
+            .filter(|x| x.end() > flash_size)
+            .map(|x| max(x.offset, flash_size)..x.end())
+            .collect();
+        if !out_of_bounds.is_empty() {
+            return Err(Error::FirmwareOutOfBounds(flash_size, out_of_bounds));
+        }
+        Ok(())
+    }
+
+    /// Overwrites bytes starting at `offset`
+    ///
+    /// Bytes outside of existing sections get new sections, which are
+    /// aligned to pages and padded with `filler` like on load.
+    pub fn patch(&mut self, offset: usize, data: &[u8], filler: u8) {
+        let mut new_sections: Vec<Section> = Vec::new();
+        for (i, byte) in data.iter().enumerate() {
+            let address = offset + i;
+            if let Some(section) = self.sections.iter_mut().find(|x| x.contains(address)) {
+                section.data[address - section.offset] = *byte;
+                continue;
+            }
+            match new_sections.last_mut() {
+                Some(section) if section.end() == address => section.data.push(*byte),
+                _ => new_sections.push(Section::new(address, vec![*byte])),
+            }
+        }
+        if new_sections.is_empty() {
+            return;
+        }
+
+        let mut sections = std::mem::take(&mut self.sections);
+        sections.append(&mut new_sections);
+        self.sections = Self::align_and_merge_sections(sections, self.page_size, filler);
+        self.len = Self::sections_len(&self.sections);
+    }
+
+    fn align_and_merge_sections(
+        mut sections: Vec<Section>,
+        page_size: usize,
+        filler: u8,
+    ) -> Vec<Section> {
         sections.sort_by_key(|x| x.offset);
-        let filler = 0xFF;
         let mut result: Vec<Section> = Vec::new();
         for mut section in sections {
             let aligned_offset = section.offset / page_size * page_size;
@@ -150,6 +632,80 @@ impl Firmware {
     }
 }
 
+/// Big-endian bytes of a serial number, and the number after it
+///
+/// Both have to fit into `width` bytes, so that a counter kept between runs
+/// never reaches a value the next run would refuse.
+pub fn serial_number(value: u64, width: usize) -> Result<(Vec<u8>, u64)> {
+    let fits = |x: u64| width >= 8 || x >> (width * 8) == 0;
+    if !fits(value) {
+        return Err(Error::SerialNumberTooWide(value, width));
+    }
+    let next = value
+        .checked_add(1)
+        .filter(|x| fits(*x))
+        .ok_or(Error::SerialNumberExhausted(value, width))?;
+    Ok((value.to_be_bytes()[8 - width.min(8)..].to_vec(), next))
+}
+
+pub fn encode_intel_hex(sections: &[Section]) -> Result<String> {
+    let mut records = Vec::new();
+    let mut upper_address = 0;
+    for section in sections {
+        let mut offset = section.offset();
+        for chunk in section.data().chunks(RECORD_LEN) {
+            // Records must not cross 64 KB boundary, so split them there
+            let split = chunk.len().min(0x10000 - (offset & 0xFFFF));
+            for (offset, value) in [(offset, &chunk[..split]), (offset + split, &chunk[split..])] {
+                if value.is_empty() {
+                    continue;
+                }
+                if offset >> 16 != upper_address {
+                    upper_address = offset >> 16;
+                    records.push(ihex::Record::ExtendedLinearAddress(upper_address as u16));
+                }
+                records.push(ihex::Record::Data {
+                    offset: offset as u16,
+                    value: value.to_vec(),
+                });
+            }
+            offset += chunk.len();
+        }
+    }
+    records.push(ihex::Record::EndOfFile);
+    ihex::create_object_file_representation(&records).map_err(Error::IHexEncodeError)
+}
+
+pub fn encode_srecord(sections: &[Section]) -> String {
+    let end = sections.iter().map(Section::end).max().unwrap_or_default();
+    let (data_type, end_type, address_len) = match end {
+        0..=0x10000 => ('1', '9', 2),
+        0x10001..=0x1000000 => ('2', '8', 3),
+        _ => ('3', '7', 4),
+    };
+    let record = |record_type: char, address: usize, data: &[u8]| {
+        let address_bytes = &(address as u32).to_be_bytes()[4 - address_len..];
+        let count = (address_len + data.len() + 1) as u8;
+        let mut line = format!("S{record_type}{count:02X}");
+        let mut checksum = count;
+        for byte in address_bytes.iter().chain(data) {
+            line.push_str(&format!("{byte:02X}"));
+            checksum = checksum.wrapping_add(*byte);
+        }
+        line.push_str(&format!("{:02X}\n", !checksum));
+        line
+    };
+
+    let mut res = "S0030000FC\n".to_string();
+    for section in sections {
+        for (i, chunk) in section.data().chunks(RECORD_LEN).enumerate() {
+            res.push_str(&record(data_type, section.offset() + i * RECORD_LEN, chunk));
+        }
+    }
+    res.push_str(&record(end_type, 0, &[]));
+    res
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -182,7 +738,7 @@ mod tests {
                 data: vec![8],
             },
         ];
-        let res = Firmware::align_and_merge_sections(src, 2);
+        let res = Firmware::align_and_merge_sections(src, 2, 0xFF);
         assert_eq!(
             res,
             vec![
@@ -201,4 +757,242 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_split_erased() {
+        let mut data = vec![0xFF; 64];
+        data[2] = 1;
+        data[18] = 2;
+        data[40] = 3;
+        assert_eq!(
+            Section::split_erased(0x100, &data, 0xFF),
+            vec![
+                Section::new(0x102, data[2..19].to_vec()),
+                Section::new(0x128, vec![3]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode() {
+        let sections = vec![
+            Section::new(0x10, vec![1, 2]),
+            Section::new(0xFFFF, vec![3, 4]),
+        ];
+        assert_eq!(
+            encode_intel_hex(&sections).unwrap(),
+            ":020010000102EB\n:01FFFF0003FE\n:020000040001F9\n:0100000004FB\n:00000001FF\n"
+        );
+        assert_eq!(
+            encode_srecord(&sections),
+            "S0030000FC\nS2060000100102E6\nS20600FFFF0304F4\nS804000000FB\n"
+        );
+    }
+
+    #[test]
+    fn test_from_srecord() {
+        let srec = b"S0030000FC\nS1050010AABB85\n\nS2060000200102D6\nS9030000FC\n".to_vec();
+        let firmware = Firmware::from_srecord(srec, 4, 0x100, 0xFF).unwrap();
+        assert_eq!(
+            firmware.sections(),
+            [
+                Section::new(0x110, vec![0xAA, 0xBB, 0xFF, 0xFF]),
+                Section::new(0x120, vec![0x01, 0x02, 0xFF, 0xFF]),
+            ]
+        );
+
+        let srec = b"S0030000FC\nS1050010AABB86\n".to_vec();
+        assert!(matches!(
+            Firmware::from_srecord(srec, 4, 0, 0xFF),
+            Err(Error::SRecordParseError(SRecordError::ChecksumMismatch, 2))
+        ));
+    }
+
+    #[test]
+    fn test_check_bounds() {
+        let firmware = Firmware {
+            len: 0,
+            page_size: 4,
+            sections: vec![
+                Section::new(0x0000, vec![0; 0x10]),
+                Section::new(0x0FF0, vec![0; 0x20]),
+                Section::new(0x2000, vec![0; 0x10]),
+            ],
+        };
+        assert!(firmware.check_bounds(0x2010).is_ok());
+        let err = firmware.check_bounds(0x1000).unwrap_err();
+        assert!(matches!(
+            &err,
+            Error::FirmwareOutOfBounds(0x1000, ranges) if ranges == &[0x1000..0x1010, 0x2000..0x2010]
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Firmware does not fit into 0x1000 bytes of flash, out of bounds at \
+            0x1000..0x1010, 0x2000..0x2010"
+        );
+    }
+
+    #[test]
+    fn test_patch() {
+        let mut firmware = Firmware::from_raw_bytes(vec![1; 6], 4, 0x10, 0xFF).unwrap();
+        firmware.patch(0x14, &[2, 2], 0xFF);
+        assert_eq!(
+            firmware.sections(),
+            [Section::new(0x10, vec![1, 1, 1, 1, 2, 2, 0xFF, 0xFF])]
+        );
+
+        // Straddles the end of existing data, and adds a separate page
+        firmware.patch(0x17, &[3, 3], 0xFF);
+        firmware.patch(0x02, &[4], 0xFF);
+        assert_eq!(
+            firmware.sections(),
+            [
+                Section::new(0x00, vec![0xFF, 0xFF, 4, 0xFF]),
+                Section::new(0x10, vec![1, 1, 1, 1, 2, 2, 0xFF, 3, 3, 0xFF, 0xFF, 0xFF]),
+            ]
+        );
+        assert_eq!(firmware.len(), 16);
+    }
+
+    #[test]
+    fn test_serial_number() {
+        assert_eq!(
+            serial_number(0x1234, 2).unwrap(),
+            (vec![0x12, 0x34], 0x1235)
+        );
+        assert_eq!(serial_number(254, 1).unwrap(), (vec![254], 255));
+        assert!(matches!(
+            serial_number(255, 1),
+            Err(Error::SerialNumberExhausted(255, 1))
+        ));
+        assert!(matches!(
+            serial_number(256, 1),
+            Err(Error::SerialNumberTooWide(256, 1))
+        ));
+        assert!(matches!(
+            serial_number(u64::MAX, 8),
+            Err(Error::SerialNumberExhausted(u64::MAX, 8))
+        ));
+    }
+
+    #[test]
+    fn test_boot_backup() {
+        let backup = BootBackup::new(0x6212, vec![0x12, 0x34]);
+        let raw = backup.to_bytes();
+        assert_eq!(raw.len(), 18);
+        assert_eq!(BootBackup::from_bytes(&raw).unwrap(), backup);
+        assert!(matches!(
+            BootBackup::from_bytes(&raw[..17]),
+            Err(Error::BootBackupParseError(_))
+        ));
+        assert!(matches!(
+            BootBackup::from_bytes(&[0; 18]),
+            Err(Error::BootBackupParseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(
+            FirmwareFormat::detect(b"\r\n:0300000002001EDB\r\n:00000001FF\r\n"),
+            Some(FirmwareFormat::IntelHex)
+        );
+        assert_eq!(
+            FirmwareFormat::detect(b"S00600004844521B\nS9030000FC\n"),
+            Some(FirmwareFormat::SRecord)
+        );
+        assert_eq!(
+            FirmwareFormat::detect(&[0x7F, b'E', b'L', b'F', 1, 1]),
+            Some(FirmwareFormat::Elf)
+        );
+        assert_eq!(
+            FirmwareFormat::detect(&omf_record(OMF_MODULE_HEADER, b"\x04TEST\x00\x00")),
+            Some(FirmwareFormat::Omf)
+        );
+        assert_eq!(FirmwareFormat::detect(&[0x02, 0x00, 0x1E, 0xFF]), None);
+        assert_eq!(FirmwareFormat::detect(b":02x"), None);
+        assert_eq!(
+            FirmwareFormat::from_path("out/app.S19"),
+            Some(FirmwareFormat::SRecord)
+        );
+        assert_eq!(FirmwareFormat::from_path("app"), None);
+    }
+
+    fn omf_record(record_type: u8, content: &[u8]) -> Vec<u8> {
+        let len = (content.len() + 1) as u16;
+        let mut record = vec![record_type];
+        record.extend(len.to_le_bytes());
+        record.extend(content);
+        let sum = record.iter().fold(0u8, |acc, x| acc.wrapping_add(*x));
+        record.push(sum.wrapping_neg());
+        record
+    }
+
+    #[test]
+    fn test_from_omf() {
+        let mut omf = omf_record(OMF_MODULE_HEADER, b"\x04TEST\x00\x00");
+        omf.extend(omf_record(
+            OMF_CONTENT,
+            &[0x00, 0x10, 0x00, 0x02, 0x00, 0x30],
+        ));
+        omf.extend(omf_record(0x04, b"\x04TEST\x00\x00\x00\x00"));
+        assert!(Firmware::is_omf(&omf));
+        let firmware = Firmware::from_omf(omf.clone(), 4, 0, 0xFF).unwrap();
+        assert_eq!(
+            firmware.sections(),
+            [Section::new(0x10, vec![0x02, 0x00, 0x30, 0xFF])]
+        );
+
+        *omf.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            Firmware::from_omf(omf, 4, 0, 0xFF),
+            Err(Error::OmfParseError(_, 21))
+        ));
+    }
+
+    #[test]
+    fn test_from_elf() {
+        // ELF32 little-endian header, one PT_LOAD and one PT_NOTE segment
+        let mut elf = vec![0; 0x34];
+        elf[..6].copy_from_slice(&[0x7F, b'E', b'L', b'F', 1, 1]);
+        elf[0x1C..0x20].copy_from_slice(&0x34u32.to_le_bytes());
+        elf[0x2A..0x2C].copy_from_slice(&0x20u16.to_le_bytes());
+        elf[0x2C..0x2E].copy_from_slice(&2u16.to_le_bytes());
+        for (p_type, p_paddr) in [(1u32, 0x0102u32), (4, 0x0200)] {
+            let mut header = [0; 0x20];
+            header[0x00..0x04].copy_from_slice(&p_type.to_le_bytes());
+            header[0x04..0x08].copy_from_slice(&0x74u32.to_le_bytes());
+            header[0x0C..0x10].copy_from_slice(&p_paddr.to_le_bytes());
+            header[0x10..0x14].copy_from_slice(&2u32.to_le_bytes());
+            elf.extend(header);
+        }
+        elf.extend([0x12, 0x34]);
+        let firmware = Firmware::from_elf(elf, 4, 0, 0xFF).unwrap();
+        assert_eq!(
+            firmware.sections(),
+            [Section::new(0x100, vec![0xFF, 0xFF, 0x12, 0x34])]
+        );
+
+        // ELF64 header with offsets that overflow when added up
+        let mut elf = vec![0; 0x40];
+        elf[..6].copy_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1]);
+        elf[0x20..0x28].copy_from_slice(&u64::MAX.to_le_bytes());
+        elf[0x36..0x38].copy_from_slice(&0x38u16.to_le_bytes());
+        elf[0x38..0x3A].copy_from_slice(&2u16.to_le_bytes());
+        assert!(matches!(
+            Firmware::from_elf(elf.clone(), 4, 0, 0xFF),
+            Err(Error::ElfParseError("program header out of file bounds"))
+        ));
+        elf[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+        elf[0x38..0x3A].copy_from_slice(&1u16.to_le_bytes());
+        let mut header = [0; 0x38];
+        header[0x00..0x04].copy_from_slice(&1u32.to_le_bytes());
+        header[0x08..0x10].copy_from_slice(&(u64::MAX - 1).to_le_bytes());
+        header[0x20..0x28].copy_from_slice(&2u64.to_le_bytes());
+        elf.extend(header);
+        assert!(matches!(
+            Firmware::from_elf(elf, 4, 0, 0xFF),
+            Err(Error::ElfParseError("segment data out of file bounds"))
+        ));
+    }
 }
@@ -1,10 +1,24 @@
 use crate::{
+    asm,
     error::{Error, Result},
-    firmware::Firmware,
+    firmware::{BootBackup, Firmware},
     transport::Transport,
+    verify::VerifyReport,
 };
 use std::time::Duration;
 
+/// Connection handshake sent right after reset
+pub(crate) const HANDSHAKE: [u8; 121] = [
+    0x55, 0x08, 0x29, 0x23, 0xBE, 0x84, 0xE1, 0x6C, 0xD6, 0xAE, 0x52, 0x90, 0x49, 0xF1, 0xF1, 0xBB,
+    0xE9, 0xEB, 0xB3, 0xA6, 0xDB, 0x3C, 0x87, 0x0C, 0x3E, 0x99, 0x24, 0x5E, 0x0D, 0x1C, 0x06, 0xB7,
+    0x47, 0xDE, 0xB3, 0x12, 0x4D, 0xC8, 0x43, 0xBB, 0x8B, 0xA6, 0x1F, 0x03, 0x5A, 0x7D, 0x09, 0x38,
+    0x25, 0x1F, 0x5D, 0xD4, 0xCB, 0xFC, 0x96, 0xF5, 0x45, 0x3B, 0x13, 0x0D, 0x89, 0x0A, 0x1C, 0xDB,
+    0xAE, 0x32, 0x20, 0x9A, 0x50, 0xEE, 0x40, 0x78, 0x36, 0xFD, 0x12, 0x49, 0x32, 0xF6, 0x9E, 0x7D,
+    0x49, 0xDC, 0xAD, 0x4F, 0x14, 0xF2, 0x44, 0x40, 0x66, 0xD0, 0x6B, 0xC4, 0x30, 0xB7, 0x32, 0x3B,
+    0xA1, 0x22, 0xF6, 0x22, 0x91, 0x9D, 0xE1, 0x8B, 0x1F, 0xDA, 0xB0, 0xCA, 0x99, 0x02, 0xB9, 0x72,
+    0x9D, 0x49, 0x2C, 0x80, 0x7E, 0x6B, 0x8F, 0xD3, 0x92,
+];
+
 #[repr(u8)]
 #[derive(Clone, Copy, PartialEq, Debug)]
 enum Sfr {
@@ -26,6 +40,10 @@ enum Sfr {
     Peromh = 0x96,
     /// In-System Program RAM mapping address
     Peram = 0x97,
+    /// Program status word, selects the register bank
+    Psw = 0xD0,
+    /// Accumulator, clobbered by most debug access
+    Acc = 0xE0,
 }
 
 #[repr(u8)]
@@ -35,6 +53,31 @@ pub enum RomBank {
     Boot = 1,
 }
 
+#[derive(gset::Getset, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct WriteStats {
+    #[getset(get_copy, vis = "pub")]
+    pages_written: usize,
+    #[getset(get_copy, vis = "pub")]
+    pages_skipped: usize,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum BlankCheck {
+    Blank,
+    /// Everything reads as zeroes, which is how read-protected chips look
+    ReadProtected,
+    /// Total count of non-blank bytes, and the first few of their offsets
+    NotBlank(usize, Vec<usize>),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProtectionStatus {
+    Unprotected,
+    /// Start of flash reads as zeroes: the chip is read-protected, blank and
+    /// erasing to zeroes, or its firmware starts with zeroes
+    Unknown,
+}
+
 #[derive(gset::Getset)]
 pub struct Flasher {
     // Inner fields
@@ -62,6 +105,21 @@ pub struct Flasher {
     #[getset(get_copy, vis = "pub")]
     #[getset(set, vis = "pub")]
     dangerous_allow_write_non_main_bank: bool,
+
+    /// Whether the chip has the CKON register, which is adjusted during reads
+    #[getset(get_copy, vis = "pub")]
+    #[getset(set, vis = "pub")]
+    has_ckon: bool,
+
+    /// Allow erasing single pages, which relies on an undocumented ISP command
+    #[getset(get_copy, vis = "pub")]
+    #[getset(set, vis = "pub")]
+    allow_unverified_page_erase: bool,
+
+    /// Read pages back before writing, and only erase and write changed ones
+    #[getset(get_copy, vis = "pub")]
+    #[getset(set, vis = "pub")]
+    smart_write: bool,
 }
 
 impl Flasher {
@@ -69,6 +127,8 @@ impl Flasher {
 
     pub const DEFAULT_RESET_DURATION_MS: u64 = 100;
     pub const DEFAULT_CONNECT_DELAY_US: u64 = 1500;
+    pub const BLANK_CHECK_MAX_OFFSETS: usize = 16;
+    pub const PROTECTION_CHECK_SIZE: usize = 64;
 
     pub fn new(transport: Box<dyn Transport>) -> Self {
         Self {
@@ -81,6 +141,9 @@ impl Flasher {
             reset_duration_ms: Self::DEFAULT_RESET_DURATION_MS,
             connect_delay_us: Self::DEFAULT_CONNECT_DELAY_US,
             dangerous_allow_write_non_main_bank: false,
+            has_ckon: true,
+            allow_unverified_page_erase: false,
+            smart_write: false,
         }
     }
 
@@ -133,17 +196,7 @@ impl Flasher {
     // Low-level commands =====================================================
 
     fn cmd_connect(&mut self) -> Result<()> {
-        self.write(&[
-            0x55, 0x08, 0x29, 0x23, 0xBE, 0x84, 0xE1, 0x6C, 0xD6, 0xAE, 0x52, 0x90, 0x49, 0xF1,
-            0xF1, 0xBB, 0xE9, 0xEB, 0xB3, 0xA6, 0xDB, 0x3C, 0x87, 0x0C, 0x3E, 0x99, 0x24, 0x5E,
-            0x0D, 0x1C, 0x06, 0xB7, 0x47, 0xDE, 0xB3, 0x12, 0x4D, 0xC8, 0x43, 0xBB, 0x8B, 0xA6,
-            0x1F, 0x03, 0x5A, 0x7D, 0x09, 0x38, 0x25, 0x1F, 0x5D, 0xD4, 0xCB, 0xFC, 0x96, 0xF5,
-            0x45, 0x3B, 0x13, 0x0D, 0x89, 0x0A, 0x1C, 0xDB, 0xAE, 0x32, 0x20, 0x9A, 0x50, 0xEE,
-            0x40, 0x78, 0x36, 0xFD, 0x12, 0x49, 0x32, 0xF6, 0x9E, 0x7D, 0x49, 0xDC, 0xAD, 0x4F,
-            0x14, 0xF2, 0x44, 0x40, 0x66, 0xD0, 0x6B, 0xC4, 0x30, 0xB7, 0x32, 0x3B, 0xA1, 0x22,
-            0xF6, 0x22, 0x91, 0x9D, 0xE1, 0x8B, 0x1F, 0xDA, 0xB0, 0xCA, 0x99, 0x02, 0xB9, 0x72,
-            0x9D, 0x49, 0x2C, 0x80, 0x7E, 0x6B, 0x8F, 0xD3, 0x92,
-        ])?;
+        self.write(&HANDSHAKE)?;
         let mut res = [0; 4];
         match self.read(&mut res) {
             Err(Error::IOError(err)) if err.kind() == std::io::ErrorKind::TimedOut => {
@@ -223,12 +276,29 @@ impl Flasher {
         Ok(())
     }
 
-    fn cmd_read_ram(&mut self, address: u8) -> Result<u8> {
-        self.cmd_exec_op(0xE5, address, 0x00)?; // MOV A, direct
+    fn cmd_read_acc(&mut self) -> Result<u8> {
         self.cmd_unk_48(0x83)?;
         self.cmd_get_u8()
     }
 
+    fn cmd_read_ram(&mut self, address: u8) -> Result<u8> {
+        self.cmd_exec_op(0xE5, address, 0x00)?; // MOV A, direct
+        self.cmd_read_acc()
+    }
+
+    fn cmd_read_iram_indirect(&mut self, address: u8) -> Result<u8> {
+        self.cmd_exec_op(0x78, address, 0x00)?; // MOV R0, #data
+        self.cmd_exec_op(0xE6, 0x00, 0x00)?; // MOV A, @R0
+        self.cmd_read_acc()
+    }
+
+    fn cmd_write_iram_indirect(&mut self, address: u8, data: u8) -> Result<()> {
+        self.cmd_exec_op(0x78, address, 0x00)?; // MOV R0, #data
+        self.cmd_exec_op(0x74, data, 0x00)?; // MOV A, #data
+        self.cmd_exec_op(0xF6, 0x00, 0x00)?; // MOV @R0, A
+        Ok(())
+    }
+
     fn cmd_write_sfr(&mut self, sfr: Sfr, data: u8) -> Result<()> {
         self.cmd_write_ram(sfr as u8, data)?;
         Ok(())
@@ -313,9 +383,13 @@ impl Flasher {
 
     fn cmd_read(&mut self, offset: u16, data: &mut [u8], progress: &dyn Fn(u64)) -> Result<()> {
         // Context save
-        let old_ckon_val = self.cmd_read_sfr(Sfr::Ckon)?;
-        // TODO: CKON only exists for some MCUs, better avoid setting it?
-        self.cmd_write_sfr(Sfr::Ckon, 0x71)?;
+        let old_ckon_val = if self.has_ckon {
+            let value = self.cmd_read_sfr(Sfr::Ckon)?;
+            self.cmd_write_sfr(Sfr::Ckon, 0x71)?;
+            Some(value)
+        } else {
+            None
+        };
         let old_dps_val = self.cmd_read_sfr(Sfr::Dps)?;
         let old_dpc_val = self.cmd_read_sfr(Sfr::Dpc)?;
         let old_dpl_val = self.cmd_read_sfr(Sfr::Dpl)?;
@@ -338,7 +412,9 @@ impl Flasher {
         self.cmd_unk_48(0x00)?;
 
         // Context restore
-        self.cmd_write_sfr(Sfr::Ckon, old_ckon_val)?;
+        if let Some(value) = old_ckon_val {
+            self.cmd_write_sfr(Sfr::Ckon, value)?;
+        }
         self.cmd_write_sfr(Sfr::Dps, old_dps_val)?;
         self.cmd_write_sfr(Sfr::Dpc, old_dpc_val)?;
         self.cmd_write_sfr(Sfr::Dpl, old_dpl_val)?;
@@ -353,6 +429,26 @@ impl Flasher {
         Ok(())
     }
 
+    /// Page erase PECMD is undocumented and not verified on hardware, so it
+    /// has to be allowed explicitly and is kept away from boot parameter area
+    fn check_page_erase(&self) -> Result<()> {
+        if self.rom_bank != RomBank::Main {
+            return Err(Error::NonMainBankPageErase);
+        }
+        if !self.allow_unverified_page_erase {
+            return Err(Error::UnverifiedPageErase);
+        }
+        Ok(())
+    }
+
+    fn cmd_erase_page(&mut self, offset: usize) -> Result<()> {
+        self.cmd_write_sfr(Sfr::Peram, 0x00)?;
+        self.cmd_write_sfr(Sfr::Peromh, (offset >> 8) as u8)?;
+        self.cmd_write_sfr(Sfr::Peroml, (offset as u8) | 0x0A)?;
+        self.cmd_write_sfr(Sfr::Pecmd, 0xA5)?;
+        Ok(())
+    }
+
     fn cmd_reload_protection(&mut self) -> Result<()> {
         self.cmd_write_xram(0xFFF8, 0x5A)?;
         self.cmd_write_xram(0xFFFB, 0xA5)?;
@@ -397,7 +493,7 @@ impl Flasher {
                     Ok(_) => break Ok(()),
                     Err(Error::HandshakeResponseTimeout) => {}
                     Err(Error::HandshakeResponseMismatch(_)) => {}
-                    Err(Error::WriteReadMismatch) => {}
+                    Err(Error::WriteReadMismatch(..)) => {}
                     Err(Error::WriteReadFailed(err))
                         if err.kind() == std::io::ErrorKind::TimedOut => {}
                     Err(Error::IOError(err)) if err.kind() == std::io::ErrorKind::TimedOut => {}
@@ -440,6 +536,112 @@ impl Flasher {
         Ok(())
     }
 
+    pub fn blank_check(
+        &mut self,
+        offset: u16,
+        size: usize,
+        erased_value: u8,
+        progress: &dyn Fn(u64),
+    ) -> Result<BlankCheck> {
+        let mut data = vec![0; size];
+        self.read_flash(offset, &mut data, progress)?;
+
+        let mut non_blank = data
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| **x != erased_value)
+            .map(|(i, _)| i + offset as usize);
+        let first: Vec<usize> = non_blank
+            .by_ref()
+            .take(Self::BLANK_CHECK_MAX_OFFSETS)
+            .collect();
+        let count = first.len() + non_blank.count();
+
+        // Read protection does not affect boot parameter area
+        if count == 0 {
+            Ok(BlankCheck::Blank)
+        } else if count == size && self.rom_bank == RomBank::Main && data.iter().all(|x| *x == 0) {
+            Ok(BlankCheck::ReadProtected)
+        } else {
+            Ok(BlankCheck::NotBlank(count, first))
+        }
+    }
+
+    /// Checks read protection by looking at the start of main flash
+    ///
+    /// Protected chips read as zeroes, so anything else means the chip is
+    /// unprotected. All zeroes alone doesn't confirm protection though.
+    pub fn protection_status(&mut self) -> Result<ProtectionStatus> {
+        let mut data = [0; Self::PROTECTION_CHECK_SIZE];
+        self.with_rom_bank(RomBank::Main, |x| x.read_flash(0, &mut data, &|_| {}))?;
+
+        if data.iter().any(|x| *x != 0) {
+            Ok(ProtectionStatus::Unprotected)
+        } else {
+            Ok(ProtectionStatus::Unknown)
+        }
+    }
+
+    /// Reads `size` bytes of boot parameter area, tagged with the chip ID
+    pub fn backup_boot(&mut self, size: usize, progress: &dyn Fn(u64)) -> Result<BootBackup> {
+        let chip_id = self.cmd_chip_id()?;
+        let mut data = vec![0; size];
+        self.with_rom_bank(RomBank::Boot, |x| x.read_flash(0, &mut data, progress))?;
+        Ok(BootBackup::new(chip_id, data))
+    }
+
+    /// Reads the unique device ID stored in boot parameter area
+    pub fn unique_id(&mut self, offset: u16, len: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0; len];
+        self.with_rom_bank(RomBank::Boot, |x| x.read_flash(offset, &mut data, &|_| {}))?;
+        Ok(data)
+    }
+
+    /// Erases the whole boot parameter area, then writes and verifies the backup
+    ///
+    /// Needs `dangerous_allow_write_non_main_bank`, and refuses backups taken
+    /// from a chip with a different ID. Anything past the end of the backup
+    /// is left erased.
+    pub fn restore_boot(
+        &mut self,
+        backup: &BootBackup,
+        page_size: usize,
+        erased_value: u8,
+        progress: &dyn Fn(u64),
+    ) -> Result<()> {
+        if !self.dangerous_allow_write_non_main_bank {
+            return Err(Error::NonMainBankWrite);
+        }
+        let chip_id = self.cmd_chip_id()?;
+        if backup.chip_id() != chip_id {
+            return Err(Error::BootBackupChipMismatch(backup.chip_id(), chip_id));
+        }
+
+        let firmware =
+            Firmware::from_raw_bytes(backup.data().to_vec(), page_size, 0, erased_value)?;
+        let old_smart_write = self.smart_write;
+        self.smart_write = false;
+        let res = self.with_rom_bank(RomBank::Boot, |x| {
+            x.erase_flash()?;
+            x.write_flash(&firmware, progress)?;
+            x.verify_flash(&firmware, progress)
+        });
+        self.smart_write = old_smart_write;
+        res
+    }
+
+    fn with_rom_bank<T>(
+        &mut self,
+        rom_bank: RomBank,
+        f: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        let old_rom_bank = self.rom_bank;
+        self.rom_bank = rom_bank;
+        let res = f(self);
+        self.rom_bank = old_rom_bank;
+        res
+    }
+
     pub fn erase_flash(&mut self) -> Result<()> {
         if self.rom_bank != RomBank::Main && !self.dangerous_allow_write_non_main_bank {
             return Err(Error::NonMainBankErase);
@@ -474,10 +676,92 @@ impl Flasher {
         Ok(())
     }
 
-    pub fn write_flash(&mut self, firmware: &Firmware, progress: &dyn Fn(u64)) -> Result<()> {
+    /// Erases all pages touched by `size` bytes starting at `offset`, which
+    /// must be within `flash_size`
+    pub fn erase_range(
+        &mut self,
+        offset: usize,
+        size: usize,
+        page_size: usize,
+        flash_size: usize,
+        progress: &dyn Fn(u64),
+    ) -> Result<()> {
+        Self::check_range("Flash", offset, size, flash_size.min(0x10000))?;
+        let start = offset / page_size * page_size;
+        let end = (offset + size).next_multiple_of(page_size);
+        let pages: Vec<usize> = (start..end).step_by(page_size).collect();
+        self.erase_pages(&pages, page_size, progress)
+    }
+
+    /// Erases only the pages covered by firmware sections
+    pub fn erase_firmware_pages(
+        &mut self,
+        firmware: &Firmware,
+        progress: &dyn Fn(u64),
+    ) -> Result<()> {
+        let pages: Vec<usize> = firmware
+            .sections()
+            .iter()
+            .flat_map(|x| (x.offset()..x.end()).step_by(firmware.page_size()))
+            .collect();
+        self.erase_pages(&pages, firmware.page_size(), progress)
+    }
+
+    fn erase_pages(
+        &mut self,
+        pages: &[usize],
+        page_size: usize,
+        progress: &dyn Fn(u64),
+    ) -> Result<()> {
+        self.check_page_erase()?;
+
+        self.cmd_pre1()?;
+        self.sleep_ms(15);
+
+        self.cmd_pre2()?;
+        self.sleep_ms(15);
+
+        let old_rom_bank = self.cmd_get_rom_bank()?;
+        self.cmd_set_rom_bank(self.rom_bank as u8)?;
+
+        for offset in pages {
+            log::debug!(
+                "Erasing page {} (offset {}, size {})",
+                offset / page_size,
+                offset,
+                page_size
+            );
+            self.cmd_erase_page(*offset)?;
+            self.sleep_ms(5);
+
+            self.cmd_check_write_finished()?;
+            self.sleep_ms(5);
+
+            progress(page_size as _);
+        }
+
+        self.cmd_set_rom_bank(old_rom_bank)?;
+
+        self.cmd_post1()?;
+        self.sleep_ms(15);
+
+        self.cmd_post2()?;
+        self.sleep_ms(15);
+
+        Ok(())
+    }
+
+    pub fn write_flash(
+        &mut self,
+        firmware: &Firmware,
+        progress: &dyn Fn(u64),
+    ) -> Result<WriteStats> {
         if self.rom_bank != RomBank::Main && !self.dangerous_allow_write_non_main_bank {
             return Err(Error::NonMainBankWrite);
         }
+        if self.smart_write {
+            self.check_page_erase()?;
+        }
 
         self.cmd_pre1()?;
         self.sleep_ms(15);
@@ -488,10 +772,35 @@ impl Flasher {
         let old_rom_bank = self.cmd_get_rom_bank()?;
         self.cmd_set_rom_bank(self.rom_bank as u8)?;
 
+        let mut stats = WriteStats::default();
         for section in firmware.sections() {
             for (i, data) in section.data().chunks(firmware.page_size()).enumerate() {
                 let page = section.offset() / firmware.page_size() + i;
                 let offset = page * firmware.page_size();
+
+                if self.smart_write {
+                    let mut current = vec![0; data.len()];
+                    self.cmd_read(offset as u16, &mut current, &|_| {})?;
+                    if current == data {
+                        log::debug!("Skipping unchanged page {page} (offset {offset})");
+                        stats.pages_skipped += 1;
+                        progress(data.len() as _);
+                        continue;
+                    }
+
+                    log::debug!(
+                        "Erasing page {} (offset {}, size {})",
+                        page,
+                        offset,
+                        firmware.page_size()
+                    );
+                    self.cmd_erase_page(offset)?;
+                    self.sleep_ms(5);
+
+                    self.cmd_check_write_finished()?;
+                    self.sleep_ms(5);
+                }
+
                 log::debug!(
                     "Writing page {} (offset {}, size {})",
                     page,
@@ -504,6 +813,7 @@ impl Flasher {
                 self.cmd_check_write_finished()?;
                 self.sleep_ms(5);
 
+                stats.pages_written += 1;
                 progress(data.len() as _);
             }
         }
@@ -516,7 +826,7 @@ impl Flasher {
         self.cmd_post2()?;
         self.sleep_ms(15);
 
-        Ok(())
+        Ok(stats)
     }
 
     pub fn verify_flash(&mut self, firmware: &Firmware, progress: &dyn Fn(u64)) -> Result<()> {
@@ -529,19 +839,16 @@ impl Flasher {
         let old_rom_bank = self.cmd_get_rom_bank()?;
         self.cmd_set_rom_bank(self.rom_bank as u8)?;
 
-        let mut errors: Vec<usize> = Vec::new();
+        let mut report = VerifyReport::default();
         for section in firmware.sections() {
             let mut verify = vec![0; section.len()];
             self.cmd_read(section.offset() as u16, verify.as_mut_slice(), progress)?;
-            let errors_it = std::iter::zip(section.data(), verify)
-                .enumerate()
-                .filter(|(_, (x, y))| *x != y)
-                .map(|(j, _)| j + section.offset());
-            errors.extend(errors_it);
-        }
-
-        if !errors.is_empty() {
-            return Err(Error::VerifyMismatch(errors));
+            report.add(
+                section.offset(),
+                section.data(),
+                &verify,
+                firmware.page_size(),
+            );
         }
 
         self.cmd_set_rom_bank(old_rom_bank)?;
@@ -552,8 +859,185 @@ impl Flasher {
         self.cmd_post2()?;
         self.sleep_ms(15);
 
+        if !report.is_empty() {
+            return Err(Error::VerifyMismatch(report));
+        }
+        Ok(())
+    }
+
+    // Debug access ===========================================================
+
+    /// Runs `f`, then restores the direct-addressed bytes it clobbers, even
+    /// if it fails
+    pub(crate) fn with_saved_direct<T>(
+        &mut self,
+        addresses: &[u8],
+        f: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        let mut saved = Vec::with_capacity(addresses.len());
+        for address in addresses {
+            saved.push((*address, self.cmd_read_ram(*address)?));
+        }
+        let res = f(self);
+        let restored = saved
+            .into_iter()
+            .rev()
+            .try_for_each(|(address, value)| self.cmd_write_ram(address, value));
+        let value = res?;
+        restored?;
+        Ok(value)
+    }
+
+    /// Like [`Self::with_saved_direct`] for A and R0 of the current register bank
+    fn with_saved_r0<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        self.with_saved_direct(&[Sfr::Acc as u8], |this| {
+            // Reading PSW clobbers A, so it has to be saved first
+            let r0 = this.cmd_read_ram(Sfr::Psw as u8)? & 0x18;
+            this.with_saved_direct(&[r0], f)
+        })
+    }
+
+    fn check_range(space: &'static str, address: usize, len: usize, end: usize) -> Result<()> {
+        if address.checked_add(len).is_none_or(|x| x > end) {
+            return Err(Error::AddressOutOfRange(space, address, len));
+        }
         Ok(())
     }
+
+    /// Reads internal RAM, using indirect addressing above 0x7F
+    pub fn read_iram(&mut self, address: u8, data: &mut [u8]) -> Result<()> {
+        Self::check_range("IRAM", address.into(), data.len(), 0x100)?;
+        self.with_saved_r0(|this| {
+            for (i, byte) in data.iter_mut().enumerate() {
+                let address = address + i as u8;
+                *byte = if address < 0x80 {
+                    this.cmd_read_ram(address)?
+                } else {
+                    this.cmd_read_iram_indirect(address)?
+                };
+            }
+            Ok(())
+        })
+    }
+
+    pub fn write_iram(&mut self, address: u8, data: &[u8]) -> Result<()> {
+        Self::check_range("IRAM", address.into(), data.len(), 0x100)?;
+        // Upper part goes first, so restoring R0 can't undo a write to it
+        let split = (0x80 - (address as usize).min(0x80)).min(data.len());
+        let (lower, upper) = data.split_at(split);
+        if !upper.is_empty() {
+            let upper_address = address.max(0x80);
+            self.with_saved_r0(|this| {
+                for (i, byte) in upper.iter().enumerate() {
+                    this.cmd_write_iram_indirect(upper_address + i as u8, *byte)?;
+                }
+                Ok(())
+            })?;
+        }
+        for (i, byte) in lower.iter().enumerate() {
+            self.cmd_write_ram(address + i as u8, *byte)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_sfr(&mut self, address: u8, data: &mut [u8]) -> Result<()> {
+        if address < 0x80 {
+            return Err(Error::AddressOutOfRange("SFR", address.into(), data.len()));
+        }
+        Self::check_range("SFR", address.into(), data.len(), 0x100)?;
+        // Every read goes through the accumulator, so report the saved value
+        let acc = self.cmd_read_ram(Sfr::Acc as u8)?;
+        for (i, byte) in data.iter_mut().enumerate() {
+            let address = address + i as u8;
+            *byte = if address == Sfr::Acc as u8 {
+                acc
+            } else {
+                self.cmd_read_ram(address)?
+            };
+        }
+        self.cmd_write_ram(Sfr::Acc as u8, acc)
+    }
+
+    pub fn write_sfr(&mut self, address: u8, data: &[u8]) -> Result<()> {
+        if address < 0x80 {
+            return Err(Error::AddressOutOfRange("SFR", address.into(), data.len()));
+        }
+        Self::check_range("SFR", address.into(), data.len(), 0x100)?;
+        for (i, byte) in data.iter().enumerate() {
+            self.cmd_write_ram(address + i as u8, *byte)?;
+        }
+        Ok(())
+    }
+
+    /// Executes a single 8051 instruction in the debug core
+    ///
+    /// The instruction length must match the opcode, and instructions that
+    /// change the program counter are refused, see [`Self::exec_any`].
+    pub fn exec(&mut self, instruction: &[u8]) -> Result<()> {
+        if instruction.first().is_some_and(|x| asm::is_branch(*x)) {
+            return Err(Error::InvalidInstruction(
+                instruction.into(),
+                "changes the program counter",
+            ));
+        }
+        self.exec_any(instruction)
+    }
+
+    /// Like [`Self::exec`], but allows jumps, calls and returns
+    pub fn exec_any(&mut self, instruction: &[u8]) -> Result<()> {
+        let Some(&opcode) = instruction.first() else {
+            return Err(Error::InvalidInstruction(vec![], "empty instruction"));
+        };
+        match asm::opcode_len(opcode) {
+            None => {
+                return Err(Error::InvalidInstruction(
+                    instruction.into(),
+                    "reserved opcode",
+                ));
+            }
+            Some(len) if len != instruction.len() => {
+                return Err(Error::InvalidInstruction(
+                    instruction.into(),
+                    "length does not match opcode",
+                ));
+            }
+            _ => {}
+        }
+        let arg1 = instruction.get(1).copied().unwrap_or(0x00);
+        let arg2 = instruction.get(2).copied().unwrap_or(0x00);
+        self.cmd_exec_op(opcode, arg1, arg2)
+    }
+
+    /// Reads the accumulator without executing anything
+    pub fn read_acc(&mut self) -> Result<u8> {
+        self.cmd_read_acc()
+    }
+
+    /// Executes an instruction with [`Self::exec`] and reads the accumulator
+    pub fn exec_read_acc(&mut self, instruction: &[u8]) -> Result<u8> {
+        self.exec(instruction)?;
+        self.cmd_read_acc()
+    }
+
+    pub fn read_xram(&mut self, address: u16, data: &mut [u8]) -> Result<()> {
+        Self::check_range("XRAM", address.into(), data.len(), 0x10000)?;
+        self.with_saved_direct(&[Sfr::Acc as u8, Sfr::Dpl as u8, Sfr::Dph as u8], |this| {
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = this.cmd_read_xram(address + i as u16)?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn write_xram(&mut self, address: u16, data: &[u8]) -> Result<()> {
+        Self::check_range("XRAM", address.into(), data.len(), 0x10000)?;
+        self.with_saved_direct(&[Sfr::Acc as u8, Sfr::Dpl as u8, Sfr::Dph as u8], |this| {
+            for (i, byte) in data.iter().enumerate() {
+                this.cmd_write_xram(address + i as u16, *byte)?;
+            }
+            Ok(())
+        })
+    }
 }
 
 impl Drop for Flasher {
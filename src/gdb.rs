@@ -0,0 +1,430 @@
+use crate::{
+    error::{Error, Result},
+    firmware::Firmware,
+    flasher::Flasher,
+};
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::net::TcpListener;
+
+// Bases of memory spaces in the flat GDB address space
+pub const CODE_BASE: u32 = 0x00_0000;
+pub const XRAM_BASE: u32 = 0x01_0000;
+pub const IRAM_BASE: u32 = 0x02_0000;
+pub const SFR_BASE: u32 = 0x03_0000;
+
+/// Register file as reported to GDB, one byte each, followed by a 16-bit PC
+pub const REGISTERS: [&str; 14] = [
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "a", "b", "psw", "sp", "dpl", "dph",
+];
+const PC_REGISTER: usize = REGISTERS.len();
+const SFR_REGISTERS: [u8; 6] = [0xE0, 0xF0, 0xD0, 0x81, 0x82, 0x83];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Space {
+    Code,
+    Xram,
+    Iram,
+    Sfr,
+}
+
+/// GDB remote serial protocol server on top of a connected [`Flasher`]
+///
+/// The core can't be run or stepped through the debug interface, so only
+/// memory and register access is supported.
+pub struct GdbServer<'a> {
+    flasher: &'a mut Flasher,
+    flash_size: usize,
+    page_size: usize,
+    ack: bool,
+}
+
+impl<'a> GdbServer<'a> {
+    pub fn new(flasher: &'a mut Flasher, flash_size: usize, page_size: usize) -> Self {
+        Self {
+            flasher,
+            flash_size,
+            page_size,
+            ack: true,
+        }
+    }
+
+    /// Waits for a single GDB connection and serves it until it detaches
+    pub fn listen(&mut self, address: &str) -> Result<()> {
+        let listener = TcpListener::bind(address)?;
+        log::info!("Waiting for GDB on {}...", listener.local_addr()?);
+        let (stream, peer) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        log::info!("GDB connected from {peer}");
+        self.serve(BufReader::new(stream.try_clone()?), stream)
+    }
+
+    /// Serves GDB packets until detach, kill or end of stream
+    pub fn serve(&mut self, mut reader: impl Read, mut writer: impl Write) -> Result<()> {
+        self.ack = true;
+        while let Some(packet) = Self::read_packet(&mut reader, &mut writer, self.ack)? {
+            log::debug!("GDB <- {packet}");
+            let response = match self.handle(&packet) {
+                Some(response) => response,
+                None => break,
+            };
+            log::debug!("GDB -> {response}");
+            let checksum = response.bytes().fold(0u8, |acc, x| acc.wrapping_add(x));
+            write!(writer, "${response}#{checksum:02x}")?;
+            writer.flush()?;
+            if packet == "QStartNoAckMode" {
+                self.ack = false;
+            }
+            if packet == "D" {
+                break;
+            }
+        }
+        log::info!("GDB disconnected");
+        Ok(())
+    }
+
+    /// Reads the next packet, or `None` at end of stream
+    fn read_packet(
+        reader: &mut impl Read,
+        writer: &mut impl Write,
+        ack: bool,
+    ) -> Result<Option<String>> {
+        let mut byte = [0];
+        loop {
+            // Skip acks and interrupts, the core is never running anyway
+            loop {
+                match reader.read_exact(&mut byte) {
+                    Ok(()) if byte[0] == b'$' => break,
+                    Ok(()) => continue,
+                    Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            let mut data = Vec::new();
+            loop {
+                reader.read_exact(&mut byte)?;
+                match byte[0] {
+                    b'#' => break,
+                    b'}' => {
+                        reader.read_exact(&mut byte)?;
+                        data.push(byte[0] ^ 0x20);
+                    }
+                    x => data.push(x),
+                }
+            }
+            let mut checksum = [0; 2];
+            reader.read_exact(&mut checksum)?;
+            let checksum = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|x| u8::from_str_radix(x, 16).ok());
+            let valid = checksum == Some(data.iter().fold(0u8, |acc, x| acc.wrapping_add(*x)));
+            if ack {
+                writer.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid || !ack {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            log::warn!("GDB packet checksum mismatch, requesting retransmission");
+        }
+    }
+
+    /// Returns the response, or `None` to close the connection without one
+    fn handle(&mut self, packet: &str) -> Option<String> {
+        let response = if packet.starts_with("qSupported") {
+            "PacketSize=1000;QStartNoAckMode+".into()
+        } else if packet == "k" {
+            return None;
+        } else if packet == "?" {
+            "S05".into()
+        } else if packet == "qAttached" {
+            "1".into()
+        } else if packet == "QStartNoAckMode" || packet == "D" || packet.starts_with('H') {
+            "OK".into()
+        } else {
+            let res = match packet.bytes().next() {
+                Some(b'g') => self.read_registers(),
+                Some(b'p') => self.read_register(&packet[1..]),
+                Some(b'P') => self.write_register(&packet[1..]),
+                Some(b'm') => self.read_memory(&packet[1..]),
+                Some(b'M') => self.write_memory(&packet[1..]),
+                _ => Ok(String::new()),
+            };
+            res.unwrap_or_else(|err| {
+                log::warn!("GDB request {packet:?} failed: {err}");
+                match err {
+                    Error::AddressOutOfRange(..) => "E01".into(),
+                    _ => "E02".into(),
+                }
+            })
+        };
+        Some(response)
+    }
+
+    fn register(&mut self, index: usize) -> Result<Option<u8>> {
+        let value = match index {
+            0..8 => {
+                let mut psw = [0];
+                self.flasher.read_sfr(0xD0, &mut psw)?;
+                let mut value = [0];
+                self.flasher
+                    .read_iram((psw[0] & 0x18) + index as u8, &mut value)?;
+                Some(value[0])
+            }
+            8..PC_REGISTER => {
+                let mut value = [0];
+                self.flasher
+                    .read_sfr(SFR_REGISTERS[index - 8], &mut value)?;
+                Some(value[0])
+            }
+            _ => None,
+        };
+        Ok(value)
+    }
+
+    fn read_registers(&mut self) -> Result<String> {
+        let mut response = String::new();
+        for index in 0..PC_REGISTER {
+            response += &format!("{:02x}", self.register(index)?.unwrap_or_default());
+        }
+        // PC can't be read through the debug interface
+        response += "xxxx";
+        Ok(response)
+    }
+
+    fn read_register(&mut self, args: &str) -> Result<String> {
+        let index = parse_hex(args)? as usize;
+        Ok(match index {
+            PC_REGISTER => "xxxx".into(),
+            _ if index > PC_REGISTER => return Err(Error::GdbPacketError),
+            _ => format!("{:02x}", self.register(index)?.unwrap_or_default()),
+        })
+    }
+
+    fn write_register(&mut self, args: &str) -> Result<String> {
+        let (index, value) = args.split_once('=').ok_or(Error::GdbPacketError)?;
+        let index = parse_hex(index)? as usize;
+        let value = decode_hex(value)?;
+        match (index, &value[..]) {
+            (0..8, [value]) => {
+                let mut psw = [0];
+                self.flasher.read_sfr(0xD0, &mut psw)?;
+                self.flasher
+                    .write_iram((psw[0] & 0x18) + index as u8, &[*value])?;
+            }
+            (8..PC_REGISTER, [value]) => {
+                self.flasher
+                    .write_sfr(SFR_REGISTERS[index - 8], &[*value])?;
+            }
+            _ => return Err(Error::GdbPacketError),
+        }
+        Ok("OK".into())
+    }
+
+    fn read_memory(&mut self, args: &str) -> Result<String> {
+        let (address, len) = args.split_once(',').ok_or(Error::GdbPacketError)?;
+        let (address, len) = (parse_hex(address)?, parse_hex(len)? as usize);
+        let (space, offset) = self.decode_address(address, len)?;
+        let mut data = vec![0; len];
+        match space {
+            Space::Code => self
+                .with_saved_context(0, |flasher| flasher.read_flash(offset, &mut data, &|_| {}))?,
+            Space::Xram => self.flasher.read_xram(offset, &mut data)?,
+            Space::Iram => self.flasher.read_iram(offset as u8, &mut data)?,
+            Space::Sfr => self.flasher.read_sfr(offset as u8, &mut data)?,
+        }
+        Ok(data.iter().map(|x| format!("{x:02x}")).collect())
+    }
+
+    fn write_memory(&mut self, args: &str) -> Result<String> {
+        let (args, data) = args.split_once(':').ok_or(Error::GdbPacketError)?;
+        let (address, len) = args.split_once(',').ok_or(Error::GdbPacketError)?;
+        let (address, len) = (parse_hex(address)?, parse_hex(len)? as usize);
+        let data = decode_hex(data)?;
+        if data.len() != len {
+            return Err(Error::GdbPacketError);
+        }
+        let (space, offset) = self.decode_address(address, len)?;
+        match space {
+            Space::Code => self.write_code(offset.into(), &data)?,
+            Space::Xram => self.flasher.write_xram(offset, &data)?,
+            Space::Iram => self.flasher.write_iram(offset as u8, &data)?,
+            Space::Sfr => self.flasher.write_sfr(offset as u8, &data)?,
+        }
+        Ok("OK".into())
+    }
+
+    /// Rewrites the flash pages covering the data, keeping the rest of them
+    fn write_code(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        let page_size = self.page_size;
+        let start = offset / page_size * page_size;
+        let end = (offset + data.len()).div_ceil(page_size) * page_size;
+        // Page writes are buffered at the bottom of IRAM
+        self.with_saved_context(page_size, |flasher| {
+            let mut pages = vec![0; end - start];
+            flasher.read_flash(start as u16, &mut pages, &|_| {})?;
+            let patched = &mut pages[offset - start..][..data.len()];
+            if patched == data {
+                return Ok(());
+            }
+            patched.copy_from_slice(data);
+
+            let firmware = Firmware::from_raw_bytes(pages, page_size, start, 0x00)?;
+            flasher.erase_firmware_pages(&firmware, &|_| {})?;
+            flasher.write_flash(&firmware, &|_| {})?;
+            Ok(())
+        })
+    }
+
+    /// Runs a flash operation, keeping the debugged program's A, B, PSW, SP,
+    /// DPTR and the first `iram_len` bytes of IRAM, which it may clobber
+    fn with_saved_context<T>(
+        &mut self,
+        iram_len: usize,
+        f: impl FnOnce(&mut Flasher) -> Result<T>,
+    ) -> Result<T> {
+        let mut iram = vec![0; iram_len];
+        let mut sfrs = SFR_REGISTERS.map(|_| 0);
+        self.flasher.read_iram(0x00, &mut iram)?;
+        for (sfr, value) in SFR_REGISTERS.iter().zip(&mut sfrs) {
+            self.flasher.read_sfr(*sfr, std::slice::from_mut(value))?;
+        }
+        // Restore even if the operation failed halfway
+        let res = f(self.flasher);
+        let restored = self.flasher.write_iram(0x00, &iram).and_then(|_| {
+            SFR_REGISTERS
+                .iter()
+                .zip(sfrs)
+                .rev()
+                .try_for_each(|(sfr, value)| self.flasher.write_sfr(*sfr, &[value]))
+        });
+        let value = res?;
+        restored?;
+        Ok(value)
+    }
+
+    fn decode_address(&self, address: u32, len: usize) -> Result<(Space, u16)> {
+        let (space, base, start, end) = match address {
+            CODE_BASE..XRAM_BASE => (Space::Code, CODE_BASE, 0, self.flash_size),
+            XRAM_BASE..IRAM_BASE => (Space::Xram, XRAM_BASE, 0, 0x10000),
+            IRAM_BASE..SFR_BASE => (Space::Iram, IRAM_BASE, 0, 0x100),
+            _ => (Space::Sfr, SFR_BASE, 0x80, 0x100),
+        };
+        let offset = address.wrapping_sub(base) as usize;
+        if offset < start || offset + len > end {
+            return Err(Error::AddressOutOfRange("GDB", address as usize, len));
+        }
+        Ok((space, offset as u16))
+    }
+}
+
+fn parse_hex(text: &str) -> Result<u32> {
+    u32::from_str_radix(text, 16).map_err(|_| Error::GdbPacketError)
+}
+
+fn decode_hex(text: &str) -> Result<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|x| x.is_ascii_hexdigit()) {
+        return Err(Error::GdbPacketError);
+    }
+    Ok((0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::Simulator;
+
+    fn packet(data: &str) -> String {
+        let checksum = data.bytes().fold(0u8, |acc, x| acc.wrapping_add(x));
+        format!("${data}#{checksum:02x}")
+    }
+
+    fn serve(flasher: &mut Flasher, requests: &[&str]) -> Vec<String> {
+        let input: String = requests.iter().map(|x| packet(x) + "+").collect();
+        let mut output = Vec::new();
+        GdbServer::new(flasher, 0x1000, 0x20)
+            .serve(input.as_bytes(), &mut output)
+            .unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .split('$')
+            .skip(1)
+            .map(|x| x.split_once('#').unwrap().0.into())
+            .collect()
+    }
+
+    #[test]
+    fn test_gdb_server() {
+        let sim = Simulator::new(0x6212, 0x1000, 0x20, 0x100);
+        let mut flasher = Flasher::new(Box::new(sim.clone()));
+        flasher.set_reset_duration_ms(0);
+        flasher.set_connect_delay_us(0);
+        flasher.set_allow_unverified_page_erase(true);
+        flasher.connect().unwrap();
+        sim.set_flash(0x0100, &[0x11, 0x22, 0x33]);
+        sim.set_iram(0x09, &[0x44]);
+        sim.set_sfr(0xD0, 0x08); // Register bank 1
+        sim.set_sfr(0xE0, 0x5A);
+
+        let responses = serve(
+            &mut flasher,
+            &[
+                "qSupported:multiprocess+",
+                "?",
+                "m100,3",
+                "M11f,2:abcd",
+                "m20009,1",
+                "M20030,2:0102",
+                "m30080,2",
+                "M10200,1:77",
+                "p1",
+                "p8",
+                "P9=42",
+                "p0e",
+                "p0f",
+                "m1000,1",
+                "m30070,1",
+                "vMustReplyEmpty",
+                "D",
+                "m0,1",
+            ],
+        );
+        assert_eq!(
+            responses,
+            [
+                "PacketSize=1000;QStartNoAckMode+",
+                "S05",
+                "112233",
+                "OK",
+                "44",
+                "OK",
+                "0000",
+                "OK",
+                "44",
+                "5a",
+                "OK",
+                "xxxx",
+                "E02",
+                "E01",
+                "E01",
+                "",
+                "OK",
+            ]
+        );
+        assert_eq!(sim.flash()[0x011E..0x0122], [0xFF, 0xAB, 0xCD, 0xFF]);
+        assert_eq!(sim.flash()[0x0100..0x0103], [0x11, 0x22, 0x33]);
+        assert_eq!(sim.iram()[0x30..0x32], [0x01, 0x02]);
+        assert_eq!(sim.xram(0x0200), 0x77);
+        assert_eq!(sim.sfr(0xF0), 0x42);
+        assert_eq!(sim.sfr(0xE0), 0x5A);
+
+        // Context is restored when a flash write fails halfway
+        flasher.set_allow_unverified_page_erase(false);
+        let iram = sim.iram();
+        assert_eq!(serve(&mut flasher, &["M140,1:00"]), ["E02"]);
+        assert_eq!(sim.flash()[0x0140], 0xFF);
+        assert_eq!(sim.iram(), iram);
+        assert_eq!(sim.sfr(0xE0), 0x5A);
+    }
+}
@@ -1,5 +1,13 @@
+pub mod asm;
+pub mod capture;
 pub mod chip;
 pub mod error;
 pub mod firmware;
 pub mod flasher;
+pub mod gdb;
+pub mod network;
+pub mod reset;
+pub mod sfr;
+pub mod simulator;
 pub mod transport;
+pub mod verify;
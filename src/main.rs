@@ -1,19 +1,43 @@
 use anyhow::Context;
 use clap::{Parser, Subcommand, ValueEnum};
 use indicatif::ProgressBar;
-use sn8flash::chip::ChipInfo;
-use sn8flash::firmware::Firmware;
-use sn8flash::flasher::{Flasher, RomBank};
-use sn8flash::transport::{ResetType, SerialPortTransport};
+use sn8flash::capture::RecordingTransport;
+use sn8flash::chip::ChipDb;
+use sn8flash::firmware::{self, BootBackup, Firmware, FirmwareFormat, Section};
+use sn8flash::flasher::{BlankCheck, Flasher, ProtectionStatus, RomBank};
+use sn8flash::gdb::GdbServer;
+use sn8flash::network::{NetworkProtocol, TcpTransport};
+use sn8flash::reset::{CommandReset, LineReset, Reset, ResetType};
+use sn8flash::sfr;
+use sn8flash::transport::{SerialPortTransport, Transport};
 use std::io::{Read, Write};
 use std::process::ExitCode;
 use structural_convert::StructuralConvert;
 
-#[derive(Clone, Copy, Debug, StructuralConvert, ValueEnum, PartialEq)]
-#[convert(into(ResetType))]
-enum ArgResetType {
-    Rts,
-    Dtr,
+#[derive(Clone, Debug, PartialEq)]
+enum ArgReset {
+    Line(ResetType),
+    Gpio(String, u32),
+    Command(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum ArgPatchData {
+    Hex(Vec<u8>),
+    File(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct ArgPatch {
+    offset: u16,
+    data: ArgPatchData,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ArgSerial {
+    offset: u16,
+    width: u8,
+    start: u64,
 }
 
 #[derive(Clone, Copy, Debug, StructuralConvert, ValueEnum, PartialEq)]
@@ -25,17 +49,81 @@ enum ArgRomBank {
     Boot,
 }
 
+#[derive(Clone, Copy, Debug, ValueEnum, PartialEq)]
+enum ArgEraseMode {
+    /// Erase the whole chip
+    Chip,
+    /// Erase only pages covered by firmware
+    Pages,
+    /// Do not erase
+    None,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum, PartialEq)]
+enum ArgMemory {
+    /// Internal RAM, 0x00-0xFF
+    Iram,
+    /// Special function registers, 0x80-0xFF
+    Sfr,
+    /// External RAM, 0x0000-0xFFFF
+    Xram,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum, PartialEq)]
+enum ArgOutputFormat {
+    /// Raw binary
+    Bin,
+    /// Intel HEX
+    Ihex,
+    /// Motorola S-record
+    Srec,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum, PartialEq)]
+enum ArgInputFormat {
+    /// Raw binary
+    Bin,
+    /// Intel HEX
+    Ihex,
+    /// Motorola S-record
+    Srec,
+    /// ELF, PT_LOAD segments only
+    Elf,
+    /// Absolute Keil OMF-51
+    Omf,
+}
+
+impl From<ArgInputFormat> for FirmwareFormat {
+    fn from(value: ArgInputFormat) -> Self {
+        match value {
+            ArgInputFormat::Bin => Self::Raw,
+            ArgInputFormat::Ihex => Self::IntelHex,
+            ArgInputFormat::Srec => Self::SRecord,
+            ArgInputFormat::Elf => Self::Elf,
+            ArgInputFormat::Omf => Self::Omf,
+        }
+    }
+}
+
 /// Sonix SN8F5xxx flash tool
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Cli {
-    /// Serial port
+    /// Serial port, or tcp://host:port and rfc2217://host:port for network
+    /// serial servers like ser2net
+    ///
+    /// Raw TCP connections can't control reset lines, use them together with
+    /// reset-less mode
     #[arg(short = 'p', long)]
-    port: String,
+    port: Option<String>,
 
-    /// Reset signal type
-    #[arg(short = 'r', long, default_value = "rts")]
-    reset_type: ArgResetType,
+    /// Reset signal type: rts, dtr, rts+dtr, gpio:<CHIP>:<LINE> or cmd:<PROGRAM>
+    ///
+    /// "gpio:/dev/gpiochip0:17" drives line 17 of a Linux GPIO chip.
+    /// "cmd:<PROGRAM>" runs the program with "1" appended to its arguments
+    /// to assert reset and "0" to release it.
+    #[arg(short = 'r', long, default_value = "rts", value_parser = reset_parser)]
+    reset_type: ArgReset,
 
     /// Reset-less mode, for adapters without RTS/DTR signals
     ///
@@ -64,6 +152,15 @@ struct Cli {
     #[arg(short = 'x', long, value_parser = number_parser::<u8>(1, 128))]
     page_size: Option<u8>,
 
+    /// Chip series to use instead of the detected one, e.g. SN8F5703A.
+    /// See list-chips for known series
+    #[arg(long, value_name = "SERIES")]
+    chip: Option<String>,
+
+    /// Flash size in bytes, overrides the chip table for custom parts
+    #[arg(long, value_parser = number_parser::<u32>(1, 65536))]
+    flash_size: Option<u32>,
+
     /// ROM bank to work with
     #[arg(long, default_value = "main")]
     rom_bank: ArgRomBank,
@@ -76,6 +173,28 @@ struct Cli {
     #[arg(long, default_value_t = false)]
     dangerous_allow_write_non_main_bank: bool,
 
+    /// Allow erasing single pages of the main bank
+    ///
+    /// Page erase is used by "erase --offset/--size", "write --erase-mode
+    /// pages", "write --smart" and GDB flash writes. Its ISP command is
+    /// undocumented and hasn't been verified on real chips, so it may erase
+    /// more or less than a page. Never used on the boot bank.
+    #[arg(long, default_value_t = false)]
+    allow_unverified_page_erase: bool,
+
+    /// Allow firmware larger than the detected flash size, writing past the
+    /// end wraps around and overwrites the start of flash
+    #[arg(long, default_value_t = false)]
+    allow_wrap: bool,
+
+    /// Chip database file, its entries extend and override the built-in ones
+    #[arg(long, value_name = "FILE")]
+    chip_db: Option<String>,
+
+    /// Record all programmer traffic to a capture file for later replay
+    #[arg(long, value_name = "FILE")]
+    record: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 
@@ -85,11 +204,45 @@ struct Cli {
 
 #[derive(Subcommand, Debug)]
 enum Commands {
+    /// List known chips, does not need a connection
+    ListChips,
+
+    #[command(flatten)]
+    Chip(ChipCommands),
+}
+
+/// Commands that connect to the chip
+#[derive(Subcommand, Debug)]
+enum ChipCommands {
     /// Connect and read chip ID
     ChipId,
 
     /// Erase flash
-    Erase,
+    Erase {
+        /// Erase offset in bytes, rounded down to page boundary.
+        /// Omit both offset and size for full chip erase
+        #[arg(short = 'o', long, value_parser = number_parser::<u16>(0, 65535))]
+        offset: Option<u16>,
+
+        /// Erase size in bytes, rounded up to page boundary.
+        /// Omit to erase until the end of flash
+        #[arg(short = 's', long, value_parser = number_parser::<u32>(1, 65536))]
+        size: Option<u32>,
+    },
+
+    /// Check that flash is erased
+    BlankCheck {
+        /// Check size in bytes, omit for full flash check
+        #[arg(short = 's', long, value_parser = number_parser::<u32>(1, 65536))]
+        size: Option<u32>,
+
+        /// Check offset in bytes
+        #[arg(short = 'o', long, default_value_t = 0, value_parser = number_parser::<u16>(0, 65535))]
+        offset: u16,
+    },
+
+    /// Check read protection status
+    ProtectionStatus,
 
     /// Read flash
     Read {
@@ -101,42 +254,197 @@ enum Commands {
         #[arg(short = 'o', long, default_value_t = 0, value_parser = number_parser::<u16>(0, 65535))]
         offset: u16,
 
-        /// Output file path (raw binary),
-        /// use "-" for stdout dump or omit for pretty-print
+        /// Output file path, use "-" for stdout dump or omit for pretty-print
         #[arg(short = 'f', long = "file")]
         path: Option<String>,
+
+        /// Output format, guessed from file extension by default
+        /// (*.hex, *.ihex, *.ihx for Intel HEX, *.s19, *.s28, *.s37, *.srec,
+        /// *.mot for S-record, raw binary otherwise)
+        #[arg(long)]
+        format: Option<ArgOutputFormat>,
+
+        /// Leave runs of erased bytes out of Intel HEX and S-record output,
+        /// not allowed with other output formats
+        #[arg(long, default_value_t = false)]
+        skip_erased: bool,
     },
 
     /// Verify flash
     Verify {
-        /// Input file path (raw binary or Intel HEX),
-        /// use "-" for raw binary from stdout
+        /// Input file path (raw binary, Intel HEX, S-record, ELF or OMF-51),
+        /// use "-" for stdin
         #[arg(short = 'f', long = "file")]
         path: String,
 
+        /// Input format, detected from contents and extension by default
+        #[arg(long)]
+        format: Option<ArgInputFormat>,
+
         /// Verify offset in bytes
         #[arg(short = 'o', long, default_value_t = 0, value_parser = number_parser::<u16>(0, 65535))]
         offset: u16,
+
+        /// Save the full mismatch report as JSON if verify fails
+        #[arg(long, value_name = "FILE")]
+        report: Option<String>,
     },
 
     /// Write flash
     Write {
-        /// Input file path (raw binary or Intel HEX),
-        /// use "-" for raw binary from stdout
+        /// Input file path (raw binary, Intel HEX, S-record, ELF or OMF-51),
+        /// use "-" for stdin
         #[arg(short = 'f', long = "file")]
         path: String,
 
+        /// Input format, detected from contents and extension by default
+        #[arg(long)]
+        format: Option<ArgInputFormat>,
+
         /// Write offset in bytes
         #[arg(short = 'o', long, default_value_t = 0, value_parser = number_parser::<u16>(0, 65535))]
         offset: u16,
 
-        /// Do not erase chip before writing
-        #[arg(long, default_value_t = false)]
+        /// Erase mode before writing
+        #[arg(long, default_value = "chip")]
+        erase_mode: ArgEraseMode,
+
+        /// Do not erase chip before writing, same as "--erase-mode none"
+        #[arg(long, default_value_t = false, conflicts_with = "erase_mode")]
         no_erase: bool,
 
+        /// Read pages back first, and only erase and write changed ones
+        #[arg(long, default_value_t = false, conflicts_with_all = ["erase_mode", "no_erase"])]
+        smart: bool,
+
         /// Do not verify after writing
         #[arg(long, default_value_t = false)]
         no_verify: bool,
+
+        /// Patch bytes before writing: <ADDR>=<HEX> or <ADDR>=@<FILE>,
+        /// e.g. 0x3FF0=DEADBEEF. May be repeated
+        #[arg(long, value_name = "PATCH", value_parser = patch_parser)]
+        patch: Vec<ArgPatch>,
+
+        /// Write an auto-incrementing big-endian serial number:
+        /// <ADDR>:<WIDTH>:<START>, e.g. 0x3FFC:4:1000
+        #[arg(long, value_name = "SERIAL", value_parser = serial_parser)]
+        serial: Option<ArgSerial>,
+
+        /// File keeping the next serial number, overrides <START> once it exists
+        #[arg(long, value_name = "FILE", default_value = "sn8flash-serial.txt")]
+        serial_state: String,
+
+        /// Save the full mismatch report as JSON if verify fails
+        #[arg(long, value_name = "FILE")]
+        report: Option<String>,
+    },
+
+    /// Read unique device ID from boot parameter area
+    Uid {
+        /// UID offset in boot parameter area, required unless set with --chip-db
+        #[arg(short = 'o', long, value_parser = number_parser::<u16>(0, 65535))]
+        offset: Option<u16>,
+
+        /// UID length in bytes, required unless set with --chip-db
+        #[arg(short = 'l', long, value_parser = number_parser::<u8>(1, 255))]
+        len: Option<u8>,
+    },
+
+    /// Read bytes from on-chip memory
+    Peek {
+        /// Memory space
+        space: ArgMemory,
+
+        /// Start address
+        #[arg(value_parser = number_parser::<u16>(0, 65535))]
+        address: u16,
+
+        /// Byte count
+        #[arg(default_value_t = 1, value_parser = number_parser::<u32>(1, 65536))]
+        len: u32,
+    },
+
+    /// Write bytes to on-chip memory
+    Poke {
+        /// Memory space
+        space: ArgMemory,
+
+        /// Start address
+        #[arg(value_parser = number_parser::<u16>(0, 65535))]
+        address: u16,
+
+        /// Data as hex bytes, e.g. 12AB or 12:AB
+        #[arg(value_parser = hex_parser)]
+        data: Vec<u8>,
+    },
+
+    /// Execute 8051 instructions in the debug core and print the accumulator
+    Exec {
+        /// Instructions, as assembly like "MOV A, 0x9A" or raw hex like "E59A"
+        #[arg(required = true, value_parser = instruction_parser)]
+        instructions: Vec<Vec<u8>>,
+
+        /// Allow jumps, calls and returns, which leave the core running elsewhere
+        #[arg(long)]
+        allow_branch: bool,
+    },
+
+    /// Serve GDB remote protocol for memory and register access
+    GdbServer {
+        /// Address to listen on
+        #[arg(short, long, default_value = "127.0.0.1:3333")]
+        listen: String,
+    },
+
+    /// Dump internal RAM
+    DumpIram {
+        /// Output file path, use "-" for stdout dump or omit for pretty-print
+        #[arg(short = 'f', long = "file")]
+        path: Option<String>,
+    },
+
+    /// Dump special function registers with their names
+    DumpSfr {
+        /// Output file path, use "-" for stdout dump or omit for a register list
+        #[arg(short = 'f', long = "file")]
+        path: Option<String>,
+    },
+
+    /// Dump external RAM
+    DumpXram {
+        /// Dump size in bytes, taken from the chip table by default
+        #[arg(short = 's', long, value_parser = number_parser::<u32>(1, 65536))]
+        size: Option<u32>,
+
+        /// Dump offset in bytes
+        #[arg(short = 'o', long, default_value_t = 0, value_parser = number_parser::<u16>(0, 65535))]
+        offset: u16,
+
+        /// Output file path, use "-" for stdout dump or omit for pretty-print
+        #[arg(short = 'f', long = "file")]
+        path: Option<String>,
+    },
+
+    /// Save boot parameter area to a file tagged with the chip ID
+    BootBackup {
+        /// Output file path
+        #[arg(short = 'f', long = "file")]
+        path: String,
+
+        /// Boot area size in bytes, taken from the chip table by default
+        #[arg(short = 's', long, value_parser = number_parser::<u32>(1, 65536))]
+        size: Option<u32>,
+    },
+
+    /// Restore boot parameter area from a backup of the same chip ID
+    ///
+    /// Needs --dangerous-allow-write-non-main-bank. The current contents are
+    /// saved to a timestamped backup file first, and the result is verified.
+    BootRestore {
+        /// Backup file path
+        #[arg(short = 'f', long = "file")]
+        path: String,
     },
 }
 
@@ -176,49 +484,319 @@ where
     }
 }
 
-fn load_firmware(path: &str, page_size: u8, offset: u16) -> anyhow::Result<Firmware> {
+fn reset_parser(value: &str) -> Result<ArgReset, String> {
+    match value {
+        "rts" => Ok(ArgReset::Line(ResetType::Rts)),
+        "dtr" => Ok(ArgReset::Line(ResetType::Dtr)),
+        "rts+dtr" | "dtr+rts" => Ok(ArgReset::Line(ResetType::RtsDtr)),
+        _ => {
+            if let Some(gpio) = value.strip_prefix("gpio:") {
+                let (chip, line) = gpio
+                    .rsplit_once(':')
+                    .ok_or(format!("expected gpio:<CHIP>:<LINE>, got {value}"))?;
+                let line = line
+                    .parse()
+                    .map_err(|_| format!("invalid GPIO line: {line}"))?;
+                Ok(ArgReset::Gpio(chip.into(), line))
+            } else if let Some(command) = value.strip_prefix("cmd:") {
+                Ok(ArgReset::Command(command.into()))
+            } else {
+                Err(format!("invalid reset type: {value}"))
+            }
+        }
+    }
+}
+
+fn patch_parser(value: &str) -> Result<ArgPatch, String> {
+    let (offset, data) = value
+        .split_once('=')
+        .ok_or(format!("expected <ADDR>=<HEX|@FILE>, got {value}"))?;
+    let offset = number_parser::<u16>(0, 65535)(offset)?;
+    let data = match data.strip_prefix('@') {
+        Some(path) => ArgPatchData::File(path.into()),
+        None => ArgPatchData::Hex(hex_parser(data)?),
+    };
+    Ok(ArgPatch { offset, data })
+}
+
+/// Parses an 8051 instruction, either assembly or raw hex bytes
+fn instruction_parser(value: &str) -> Result<Vec<u8>, String> {
+    sn8flash::asm::assemble(value).or_else(|err| hex_parser(value).map_err(|_| err.to_string()))
+}
+
+/// Parses hex bytes, allowing ':', '-' and ' ' separators
+fn hex_parser(value: &str) -> Result<Vec<u8>, String> {
+    let hex: String = value
+        .chars()
+        .filter(|x| !matches!(x, ':' | '-' | ' '))
+        .collect();
+    if hex.is_empty() || !hex.len().is_multiple_of(2) || !hex.chars().all(|x| x.is_ascii_hexdigit())
+    {
+        return Err(format!("invalid hex data: {value}"));
+    }
+    Ok((0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect())
+}
+
+fn serial_parser(value: &str) -> Result<ArgSerial, String> {
+    let mut parts = value.split(':');
+    let (Some(offset), Some(width), Some(start), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(format!("expected <ADDR>:<WIDTH>:<START>, got {value}"));
+    };
+    Ok(ArgSerial {
+        offset: number_parser::<u16>(0, 65535)(offset)?,
+        width: number_parser::<u8>(1, 8)(width)?,
+        start: number_parser::<u64>(0, u64::MAX)(start)?,
+    })
+}
+
+fn open_reset(reset: &ArgReset) -> anyhow::Result<Box<dyn Reset>> {
+    Ok(match reset {
+        ArgReset::Line(reset_type) => Box::new(LineReset::new(*reset_type)),
+        #[cfg(target_os = "linux")]
+        ArgReset::Gpio(chip, line) => Box::new(
+            sn8flash::reset::GpioReset::new(chip, *line)
+                .context(format!("Failed to request GPIO line {line} of {chip}"))?,
+        ),
+        #[cfg(not(target_os = "linux"))]
+        ArgReset::Gpio(..) => anyhow::bail!("GPIO reset is only supported on Linux"),
+        ArgReset::Command(command) => Box::new(CommandReset::new(command)),
+    })
+}
+
+fn load_firmware(
+    path: &str,
+    format: Option<ArgInputFormat>,
+    page_size: u8,
+    offset: u16,
+    filler: u8,
+) -> anyhow::Result<Firmware> {
+    let format = format.map(FirmwareFormat::from);
     let firmware = if path == "-" {
-        log::info!("Reading raw binary from stdin...");
+        log::info!("Reading stdin...");
         let mut raw = Vec::new();
         std::io::stdin().read_to_end(&mut raw)?;
-        Firmware::from_raw_bytes(raw, page_size.into(), offset.into())?
+        let format = format
+            .or_else(|| FirmwareFormat::detect(&raw))
+            .unwrap_or(FirmwareFormat::Raw);
+        log::info!("Loading stdin as {format}");
+        Firmware::from_bytes(raw, format, page_size.into(), offset.into(), filler)?
     } else {
         log::info!("Opening {path}...");
-        Firmware::from_file(path, page_size.into(), offset.into())?
+        Firmware::from_file(path, format, page_size.into(), offset.into(), filler)?
     };
     Ok(firmware)
 }
 
-fn dump_firmware(path: Option<&str>, data: &[u8], offset: u16) -> anyhow::Result<()> {
-    match path {
-        None => {
+/// IRAM and SFR addresses are 8-bit, so wider ones are always out of range
+fn short_address(space: &'static str, address: u16, len: usize) -> sn8flash::error::Result<u8> {
+    u8::try_from(address)
+        .map_err(|_| sn8flash::error::Error::AddressOutOfRange(space, address.into(), len))
+}
+
+fn read_memory(
+    flasher: &mut Flasher,
+    space: ArgMemory,
+    address: u16,
+    data: &mut [u8],
+) -> anyhow::Result<()> {
+    log::info!("Reading {} bytes at {address:#X}...", data.len());
+    let len = data.len();
+    match space {
+        ArgMemory::Iram => flasher.read_iram(short_address("IRAM", address, len)?, data)?,
+        ArgMemory::Sfr => flasher.read_sfr(short_address("SFR", address, len)?, data)?,
+        ArgMemory::Xram => flasher.read_xram(address, data)?,
+    }
+    Ok(())
+}
+
+fn write_memory(
+    flasher: &mut Flasher,
+    space: ArgMemory,
+    address: u16,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    let len = data.len();
+    match space {
+        ArgMemory::Iram => flasher.write_iram(short_address("IRAM", address, len)?, data)?,
+        ArgMemory::Sfr => flasher.write_sfr(short_address("SFR", address, len)?, data)?,
+        ArgMemory::Xram => flasher.write_xram(address, data)?,
+    }
+    log::info!("Written {len} bytes at {address:#X}");
+    Ok(())
+}
+
+/// Mismatch ranges shown in the diff after a failed verify
+const DIFF_RANGES: usize = 8;
+/// Bytes of each mismatch range shown in the diff
+const DIFF_RANGE_BYTES: usize = 64;
+
+fn verify_firmware(
+    flasher: &mut Flasher,
+    firmware: &Firmware,
+    report_path: Option<&str>,
+) -> anyhow::Result<()> {
+    let bar = ProgressBar::new(firmware.len() as _);
+    let res = flasher.verify_flash(firmware, &|x| bar.inc(x));
+    bar.finish();
+    let Err(sn8flash::error::Error::VerifyMismatch(report)) = res else {
+        return Ok(res?);
+    };
+
+    let hex = |data: &[u8]| {
+        data.iter()
+            .map(|x| format!("{x:02X}"))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    for range in report.ranges().iter().take(DIFF_RANGES) {
+        eprintln!("{:#06X}..{:#06X}:", range.offset(), range.end());
+        let shown = range.len().min(DIFF_RANGE_BYTES);
+        for (i, (expected, actual)) in std::iter::zip(
+            range.expected()[..shown].chunks(16),
+            range.actual()[..shown].chunks(16),
+        )
+        .enumerate()
+        {
+            eprintln!("  {:#06X}  -{}", range.offset() + i * 16, hex(expected));
+            eprintln!("          +{}", hex(actual));
+        }
+        if shown < range.len() {
+            eprintln!("  ... {} more bytes", range.len() - shown);
+        }
+    }
+    if report.ranges().len() > DIFF_RANGES {
+        eprintln!("... {} more ranges", report.ranges().len() - DIFF_RANGES);
+    }
+
+    if let Some(path) = report_path {
+        let file = std::fs::File::create(path).context(format!("Failed to create {path}"))?;
+        serde_json::to_writer_pretty(file, &report).context(format!("Failed to write {path}"))?;
+        log::info!("Saved verify report to {path}");
+    }
+    anyhow::bail!("Verify mismatch: {report}")
+}
+
+/// Picks the output format, guessing it from the file extension if not given
+fn output_format(path: Option<&str>, format: Option<ArgOutputFormat>) -> Option<ArgOutputFormat> {
+    format.or_else(|| match FirmwareFormat::from_path(path?)? {
+        FirmwareFormat::IntelHex => Some(ArgOutputFormat::Ihex),
+        FirmwareFormat::SRecord => Some(ArgOutputFormat::Srec),
+        _ => None,
+    })
+}
+
+fn dump_firmware(
+    path: Option<&str>,
+    data: &[u8],
+    offset: u16,
+    format: Option<ArgOutputFormat>,
+    skip_erased: Option<u8>,
+) -> anyhow::Result<()> {
+    let format = output_format(path, format);
+
+    let sections = || match skip_erased {
+        Some(erased_value) => Section::split_erased(offset.into(), data, erased_value),
+        None => vec![Section::new(offset.into(), data.to_vec())],
+    };
+    let output = match format {
+        None if path.is_none() => {
             let cfg = nu_pretty_hex::HexConfig {
                 address_offset: offset as usize,
                 ..nu_pretty_hex::HexConfig::default()
             };
             println!("{}", nu_pretty_hex::config_hex(&data, cfg));
+            return Ok(());
         }
-        Some("-") => {
+        None | Some(ArgOutputFormat::Bin) => data.to_vec(),
+        Some(ArgOutputFormat::Ihex) => firmware::encode_intel_hex(&sections())?.into_bytes(),
+        Some(ArgOutputFormat::Srec) => firmware::encode_srecord(&sections()).into_bytes(),
+    };
+
+    match path {
+        None | Some("-") => {
             log::info!("Dumping to stdout...");
             std::io::stdout()
-                .write_all(data)
+                .write_all(&output)
                 .context("Failed to write to standard output")?;
         }
         Some(path) => {
             log::info!("Saving to {path}...");
-            std::fs::write(path, data).context(format!("Failed to save {path}"))?;
+            std::fs::write(path, output).context(format!("Failed to save {path}"))?;
         }
     }
     Ok(())
 }
 
+fn list_chips(chip_db: &ChipDb) {
+    println!("Series      Chip IDs         Flash    Page");
+    for entry in chip_db.entries() {
+        let info = entry.info();
+        println!(
+            "{:<11} {:#06X}..{:#06X}  {:>6}  {:>4}",
+            info.series(),
+            entry.id_start(),
+            entry.id_end(),
+            info.flash_size(),
+            info.page_size()
+        );
+    }
+}
+
 fn run(args: &Cli) -> anyhow::Result<()> {
-    let transport = {
-        log::info!("Opening port {}...", args.port);
-        let mut serial = SerialPortTransport::new(&args.port)?;
-        serial.set_reset_type(args.reset_type.into());
-        serial.set_reset_invert(args.reset_invert);
-        Box::new(serial)
+    let mut chip_db = ChipDb::builtin().clone();
+    if let Some(ref path) = args.chip_db {
+        log::info!("Loading chip database {path}...");
+        chip_db.extend(ChipDb::from_file(path).context(format!("Failed to load {path}"))?);
+    }
+
+    let command = match args.command {
+        Commands::ListChips => {
+            list_chips(&chip_db);
+            return Ok(());
+        }
+        Commands::Chip(ref command) => command,
+    };
+
+    let port = args
+        .port
+        .as_deref()
+        .context("Port is required, use --port")?;
+    let transport: Box<dyn Transport> = {
+        log::info!("Opening port {port}...");
+        let network = [
+            ("tcp://", NetworkProtocol::Raw),
+            ("rfc2217://", NetworkProtocol::Rfc2217),
+        ]
+        .into_iter()
+        .find_map(|(scheme, protocol)| Some((port.strip_prefix(scheme)?, protocol)));
+        if let Some((address, protocol)) = network {
+            let mut tcp = TcpTransport::new(address, protocol)
+                .context(format!("Failed to connect to {address}"))?;
+            tcp.set_reset_circuit(open_reset(&args.reset_type)?);
+            tcp.set_reset_invert(args.reset_invert);
+            Box::new(tcp)
+        } else {
+            let mut serial = SerialPortTransport::new(port)?;
+            serial.set_reset_circuit(open_reset(&args.reset_type)?);
+            serial.set_reset_invert(args.reset_invert);
+            Box::new(serial)
+        }
+    };
+
+    let transport: Box<dyn Transport> = match args.record {
+        Some(ref path) => {
+            log::info!("Recording traffic to {path}...");
+            Box::new(
+                RecordingTransport::new(transport, path)
+                    .context(format!("Failed to create {path}"))?,
+            )
+        }
+        None => transport,
     };
 
     let mut flasher = Flasher::new(transport);
@@ -227,6 +805,7 @@ fn run(args: &Cli) -> anyhow::Result<()> {
     flasher.set_connect_delay_us(args.connect_delay);
     flasher.set_rom_bank(args.rom_bank.into());
     flasher.set_dangerous_allow_write_non_main_bank(args.dangerous_allow_write_non_main_bank);
+    flasher.set_allow_unverified_page_erase(args.allow_unverified_page_erase);
 
     log::info!("Connecting...");
     let chip_id = if args.reset_less {
@@ -239,38 +818,177 @@ fn run(args: &Cli) -> anyhow::Result<()> {
         flasher.connect()?
     };
 
-    let chip_info = ChipInfo::from_chip_id(chip_id);
+    let detected_chip_info = chip_db.find(chip_id);
     log::info!(
         "Chip ID is {:#X} ({})",
         chip_id,
-        chip_info.map_or("unknown chip".to_string(), |x| x.to_string())
+        detected_chip_info.map_or("unknown chip".to_string(), |x| x.to_string())
     );
 
+    let mut chip_info = match args.chip {
+        Some(ref series) => {
+            let info = chip_db
+                .find_series(series)
+                .context(format!("Unknown chip series {series}, see list-chips"))?;
+            if let Some(detected) = detected_chip_info.filter(|x| x.series() != info.series()) {
+                log::warn!(
+                    "Chip ID belongs to {} series, using {} as requested",
+                    detected.series(),
+                    info.series()
+                );
+            }
+            Some(info.clone())
+        }
+        None => detected_chip_info.cloned(),
+    };
+    if let (Some(x), Some(flash_size)) = (chip_info.as_mut(), args.flash_size) {
+        x.set_flash_size(flash_size);
+    }
+    let chip_info = chip_info.as_ref();
+    if args.chip.is_some() || args.flash_size.is_some() {
+        log::info!(
+            "Using {}",
+            chip_info.map_or_else(
+                || format!(
+                    "custom chip, {} bytes flash",
+                    args.flash_size.unwrap_or_default()
+                ),
+                |x| x.to_string()
+            )
+        );
+    }
+
+    if let Some(has_ckon) = chip_info.and_then(|x| x.has_ckon()) {
+        flasher.set_has_ckon(has_ckon);
+    }
+
     let page_size_fn = || {
         args.page_size
             .or_else(|| chip_info.map(|x| x.page_size()))
             .context("Unknown chip: must provide page size")
     };
 
-    match args.command {
-        Commands::ChipId => {
+    let erased_value_fn = || {
+        chip_info.map_or_else(
+            || {
+                log::warn!("Unknown chip, assuming erased value is 0xFF");
+                0xFF
+            },
+            |x| x.erased_value(),
+        )
+    };
+
+    let flash_size_fn = || {
+        args.flash_size
+            .or_else(|| chip_info.map(|x| x.flash_size()))
+    };
+
+    let bank_size_fn = || match args.rom_bank {
+        ArgRomBank::Main => flash_size_fn(),
+        ArgRomBank::Boot => chip_info.and_then(|x| x.boot_size()).map(u32::from),
+    };
+
+    let check_bounds_fn = |firmware: &Firmware| -> anyhow::Result<()> {
+        if args.allow_wrap || args.rom_bank != ArgRomBank::Main {
+            return Ok(());
+        }
+        match flash_size_fn() {
+            Some(x) => firmware.check_bounds(x as _)?,
+            None => log::warn!("Unknown chip, firmware size is not checked"),
+        }
+        Ok(())
+    };
+
+    match *command {
+        ChipCommands::ChipId => {
             // Already printed it!
         }
-        Commands::Erase => {
+        ChipCommands::Erase {
+            offset: None,
+            size: None,
+        } => {
             log::info!("Erasing flash...");
             flasher.erase_flash()?;
         }
-        Commands::Read {
+        ChipCommands::Erase { offset, size } => {
+            let offset = u32::from(offset.unwrap_or_default());
+            let flash_size = bank_size_fn();
+            let size = size
+                .or_else(|| flash_size.map(|x| x.saturating_sub(offset)))
+                .context("Unknown chip: must provide erase size")?;
+            let flash_size = flash_size.unwrap_or_else(|| {
+                log::warn!("Unknown chip, erase range is only checked against 64 KiB");
+                0x10000
+            });
+            let page_size = u32::from(page_size_fn()?);
+
+            log::info!("Erasing {size} bytes of flash at offset {offset:#X}...");
+            let start = offset / page_size * page_size;
+            let end = (offset + size).next_multiple_of(page_size);
+            let bar = ProgressBar::new(end.saturating_sub(start).into());
+            flasher.erase_range(
+                offset as _,
+                size as _,
+                page_size as _,
+                flash_size as _,
+                &|x| bar.inc(x),
+            )?;
+            bar.finish();
+        }
+        ChipCommands::BlankCheck { offset, size } => {
+            let size = size
+                .or_else(bank_size_fn)
+                .context("Unknown chip: must provide check size")?;
+            let erased_value = erased_value_fn();
+
+            log::info!("Checking {size} bytes of flash...");
+            let bar = ProgressBar::new(size as _);
+            let res = flasher.blank_check(offset, size as _, erased_value, &|x| bar.inc(x))?;
+            bar.finish();
+
+            match res {
+                BlankCheck::Blank => log::info!("Flash is blank"),
+                BlankCheck::ReadProtected => {
+                    anyhow::bail!("Flash reads as all zeroes, chip is probably read-protected")
+                }
+                BlankCheck::NotBlank(count, first) => {
+                    anyhow::bail!(
+                        "Flash is not blank: {count} bytes differ from {erased_value:#04X}, \
+                        first at offsets {first:#06X?}"
+                    )
+                }
+            }
+        }
+        ChipCommands::ProtectionStatus => {
+            let status = flasher.protection_status()?;
+            log::info!(
+                "{}",
+                match status {
+                    ProtectionStatus::Unprotected => "Chip is not read-protected",
+                    ProtectionStatus::Unknown => {
+                        "Start of flash reads as zeroes, chip is either read-protected or blank"
+                    }
+                }
+            );
+        }
+        ChipCommands::Read {
             ref path,
             offset,
             size,
+            format,
+            skip_erased,
         } => {
-            let size = if args.rom_bank != ArgRomBank::Main {
-                size.context("Non-default ROM bank: must provide read size")?
-            } else {
-                size.or_else(|| chip_info.map(|x| x.flash_size()))
-                    .context("Unknown chip: must provide read size")?
-            };
+            if skip_erased
+                && !matches!(
+                    output_format(path.as_deref(), format),
+                    Some(ArgOutputFormat::Ihex | ArgOutputFormat::Srec)
+                )
+            {
+                anyhow::bail!("--skip-erased needs Intel HEX or S-record output");
+            }
+            let size = size
+                .or_else(bank_size_fn)
+                .context("Unknown chip: must provide read size")?;
 
             log::info!("Reading {size} bytes of flash...");
             let mut data_read = vec![0; size as usize];
@@ -278,31 +996,226 @@ fn run(args: &Cli) -> anyhow::Result<()> {
             flasher.read_flash(offset, &mut data_read, &|x| bar.inc(x))?;
             bar.finish();
 
-            dump_firmware(path.as_deref(), &data_read, offset)?;
+            let skip_erased = skip_erased.then(erased_value_fn);
+            dump_firmware(path.as_deref(), &data_read, offset, format, skip_erased)?;
         }
-        Commands::Verify { ref path, offset } => {
-            let firmware = load_firmware(path, page_size_fn()?, offset)?;
+        ChipCommands::Verify {
+            ref path,
+            format,
+            offset,
+            ref report,
+        } => {
+            let firmware = load_firmware(path, format, page_size_fn()?, offset, erased_value_fn())?;
+            check_bounds_fn(&firmware)?;
 
             log::info!(
                 "Verifying {} bytes of flash ({} pages)...",
                 firmware.len(),
                 firmware.len() / firmware.page_size()
             );
-            let bar = ProgressBar::new(firmware.len() as _);
-            flasher.verify_flash(&firmware, &|x| bar.inc(x))?;
+            verify_firmware(&mut flasher, &firmware, report.as_deref())?;
+        }
+        ChipCommands::Uid { offset, len } => {
+            let offset = offset
+                .or_else(|| chip_info.and_then(|x| x.uid_offset()))
+                .context("Unique ID location is not documented: must provide --offset")?;
+            let len = len
+                .or_else(|| chip_info.and_then(|x| x.uid_len()))
+                .context("Unique ID location is not documented: must provide --len")?;
+
+            let uid = flasher.unique_id(offset, len.into())?;
+            let hex: String = uid.iter().map(|x| format!("{x:02X}")).collect();
+            log::info!("Unique ID is {uid:02X?}");
+            println!("{chip_id:04X}-{hex}");
+        }
+        ChipCommands::Peek {
+            space,
+            address,
+            len,
+        } => {
+            let mut data = vec![0; len as usize];
+            read_memory(&mut flasher, space, address, &mut data)?;
+            dump_firmware(None, &data, address, None, None)?;
+        }
+        ChipCommands::Poke {
+            space,
+            address,
+            ref data,
+        } => {
+            write_memory(&mut flasher, space, address, data)?;
+        }
+        ChipCommands::Exec {
+            ref instructions,
+            allow_branch,
+        } => {
+            for instruction in instructions {
+                log::info!("Executing {instruction:02X?}...");
+                if allow_branch {
+                    flasher.exec_any(instruction)?;
+                } else {
+                    flasher.exec(instruction)?;
+                }
+            }
+            println!("A = {:#04X}", flasher.read_acc()?);
+        }
+        ChipCommands::GdbServer { ref listen } => {
+            // Code space is always main flash, the boot area isn't program memory
+            if args.rom_bank != ArgRomBank::Main {
+                log::warn!("GDB code space is main flash, ignoring --rom-bank");
+                flasher.set_rom_bank(RomBank::Main);
+            }
+            let flash_size = flash_size_fn().context("Unknown chip: must provide --flash-size")?;
+            let page_size = page_size_fn()?;
+            GdbServer::new(&mut flasher, flash_size as _, page_size.into()).listen(listen)?;
+        }
+        ChipCommands::DumpIram { ref path } => {
+            let size = chip_info.and_then(|x| x.ram_size()).unwrap_or_else(|| {
+                log::warn!("IRAM size is not in the chip database, dumping all 256 bytes");
+                0x100
+            });
+            let mut data = vec![0; (size as usize).min(0x100)];
+            flasher.read_iram(0, &mut data)?;
+            dump_firmware(path.as_deref(), &data, 0, None, None)?;
+        }
+        ChipCommands::DumpSfr { ref path } => {
+            let mut data = [0; 0x80];
+            flasher.read_sfr(0x80, &mut data)?;
+            if path.is_some() {
+                dump_firmware(path.as_deref(), &data, 0x80, None, None)?;
+            } else {
+                for (i, value) in data.iter().enumerate() {
+                    let address = 0x80 + i as u8;
+                    let name = sfr::sfr_name(address).unwrap_or_default();
+                    println!("{address:#04X}  {name:<8}{value:02X}");
+                }
+            }
+        }
+        ChipCommands::DumpXram {
+            size,
+            offset,
+            ref path,
+        } => {
+            let size = size
+                .or_else(|| chip_info.and_then(|x| x.xram_size()).map(u32::from))
+                .context("XRAM size is not in the chip database: must provide dump size")?;
+            let mut data = vec![0; size as usize];
+            read_memory(&mut flasher, ArgMemory::Xram, offset, &mut data)?;
+            dump_firmware(path.as_deref(), &data, offset, None, None)?;
+        }
+        ChipCommands::BootBackup { ref path, size } => {
+            let size = size
+                .or_else(|| chip_info.and_then(|x| x.boot_size()).map(u32::from))
+                .context("Boot area size is not in the chip database: must provide it")?;
+
+            log::info!("Reading {size} bytes of boot parameter area...");
+            let bar = ProgressBar::new(size as _);
+            let backup = flasher.backup_boot(size as _, &|x| bar.inc(x))?;
+            bar.finish();
+            backup
+                .save(path)
+                .context(format!("Failed to save {path}"))?;
+        }
+        ChipCommands::BootRestore { ref path } => {
+            if !args.dangerous_allow_write_non_main_bank {
+                anyhow::bail!("Restoring boot area needs --dangerous-allow-write-non-main-bank");
+            }
+            let backup = BootBackup::load(path).context(format!("Failed to load {path}"))?;
+
+            // Restore erases the whole area, so back up all of it when known
+            let current_size = chip_info
+                .and_then(|x| x.boot_size())
+                .map_or(backup.data().len(), |x| backup.data().len().max(x.into()));
+            log::info!("Backing up current boot parameter area...");
+            let bar = ProgressBar::new(current_size as _);
+            let current = flasher.backup_boot(current_size, &|x| bar.inc(x))?;
+            bar.finish();
+            let timestamp = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs();
+            let current_path = format!("boot-backup-{chip_id:04X}-{timestamp}.bin");
+            current
+                .save(&current_path)
+                .context(format!("Failed to save {current_path}"))?;
+            log::info!("Saved current boot parameter area to {current_path}");
+
+            log::info!("Restoring boot parameter area...");
+            let bar = ProgressBar::new(backup.data().len() as u64 * 2);
+            flasher
+                .restore_boot(&backup, page_size_fn()?.into(), erased_value_fn(), &|x| {
+                    bar.inc(x)
+                })
+                .context(format!("Failed to restore {path}"))?;
             bar.finish();
         }
-        Commands::Write {
+        ChipCommands::Write {
             ref path,
+            format,
             offset,
+            erase_mode,
             no_erase,
+            smart,
             no_verify,
+            ref patch,
+            serial,
+            ref serial_state,
+            ref report,
         } => {
-            let firmware = load_firmware(path, page_size_fn()?, offset)?;
+            let mut firmware =
+                load_firmware(path, format, page_size_fn()?, offset, erased_value_fn())?;
 
-            if !no_erase {
-                log::info!("Erasing flash...");
-                flasher.erase_flash()?;
+            for x in patch {
+                let data = match x.data {
+                    ArgPatchData::Hex(ref data) => data.clone(),
+                    ArgPatchData::File(ref path) => {
+                        std::fs::read(path).context(format!("Failed to read {path}"))?
+                    }
+                };
+                log::info!("Patching {} bytes at {:#06X}", data.len(), x.offset);
+                firmware.patch(x.offset.into(), &data, erased_value_fn());
+            }
+
+            let next_serial_number = match serial {
+                Some(x) => {
+                    let value = match std::fs::read_to_string(serial_state) {
+                        Ok(text) => text
+                            .trim()
+                            .parse()
+                            .context(format!("Invalid serial number in {serial_state}"))?,
+                        Err(err) if err.kind() == std::io::ErrorKind::NotFound => x.start,
+                        Err(err) => {
+                            return Err(err).context(format!("Failed to read {serial_state}"));
+                        }
+                    };
+                    let (bytes, next) = firmware::serial_number(value, x.width.into())?;
+                    log::info!("Writing serial number {value} at {:#06X}", x.offset);
+                    firmware.patch(x.offset.into(), &bytes, erased_value_fn());
+                    Some(next)
+                }
+                None => None,
+            };
+
+            check_bounds_fn(&firmware)?;
+
+            let erase_mode = if no_erase || smart {
+                ArgEraseMode::None
+            } else {
+                erase_mode
+            };
+            match erase_mode {
+                ArgEraseMode::Chip => {
+                    log::info!("Erasing flash...");
+                    flasher.erase_flash()?;
+                }
+                ArgEraseMode::Pages => {
+                    log::info!(
+                        "Erasing {} pages of flash...",
+                        firmware.len() / firmware.page_size()
+                    );
+                    let bar = ProgressBar::new(firmware.len() as _);
+                    flasher.erase_firmware_pages(&firmware, &|x| bar.inc(x))?;
+                    bar.finish();
+                }
+                ArgEraseMode::None => {}
             }
 
             log::info!(
@@ -311,14 +1224,23 @@ fn run(args: &Cli) -> anyhow::Result<()> {
                 firmware.len() / firmware.page_size()
             );
             let bar = ProgressBar::new(firmware.len() as _);
-            flasher.write_flash(&firmware, &|x| bar.inc(x))?;
+            flasher.set_smart_write(smart);
+            let stats = flasher.write_flash(&firmware, &|x| bar.inc(x))?;
             bar.finish();
+            log::info!(
+                "{} pages written, {} pages skipped",
+                stats.pages_written(),
+                stats.pages_skipped()
+            );
 
             if !no_verify {
                 log::info!("Verifying write...");
-                let bar = ProgressBar::new(firmware.len() as _);
-                flasher.verify_flash(&firmware, &|x| bar.inc(x))?;
-                bar.finish();
+                verify_firmware(&mut flasher, &firmware, report.as_deref())?;
+            }
+
+            if let Some(next) = next_serial_number {
+                std::fs::write(serial_state, format!("{next}\n"))
+                    .context(format!("Failed to update {serial_state}"))?;
             }
         }
     }
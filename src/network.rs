@@ -0,0 +1,454 @@
+use crate::{
+    error::{Error, Result},
+    reset::{ControlLines, LineReset, Reset, ResetType},
+    transport::{BAUD_RATE, Transport, check_echo},
+};
+use std::{
+    collections::VecDeque,
+    io::{ErrorKind, Read, Write},
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+// Telnet commands and options (RFC 854, RFC 856, RFC 858)
+const IAC: u8 = 0xFF;
+const DONT: u8 = 0xFE;
+const DO: u8 = 0xFD;
+const WONT: u8 = 0xFC;
+const WILL: u8 = 0xFB;
+const SB: u8 = 0xFA;
+const SE: u8 = 0xF0;
+const OPT_BINARY: u8 = 0x00;
+const OPT_SGA: u8 = 0x03;
+const OPT_COM_PORT: u8 = 0x2C;
+
+// COM-PORT-OPTION client commands (RFC 2217)
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const SET_CONTROL: u8 = 5;
+
+// SET-CONTROL values (RFC 2217)
+const CONTROL_NO_FLOW: u8 = 1;
+const CONTROL_DTR_ON: u8 = 8;
+const CONTROL_DTR_OFF: u8 = 9;
+const CONTROL_RTS_ON: u8 = 11;
+const CONTROL_RTS_OFF: u8 = 12;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NetworkProtocol {
+    /// Plain TCP socket, no line control (ser2net "raw" mode)
+    Raw,
+    /// Telnet with COM port control (ser2net "telnet" mode with RFC 2217)
+    Rfc2217,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum TelnetEvent {
+    Data(u8),
+    Negotiation(u8, u8),
+    Subnegotiation(Vec<u8>),
+}
+
+#[derive(Default)]
+enum TelnetState {
+    #[default]
+    Data,
+    Iac,
+    Negotiation(u8),
+    Subnegotiation(Vec<u8>),
+    SubnegotiationIac(Vec<u8>),
+}
+
+#[derive(Default)]
+struct TelnetDecoder {
+    state: TelnetState,
+}
+
+impl TelnetDecoder {
+    fn push(&mut self, byte: u8) -> Option<TelnetEvent> {
+        let (state, event) = match (std::mem::take(&mut self.state), byte) {
+            (TelnetState::Data, IAC) => (TelnetState::Iac, None),
+            (TelnetState::Data, _) => (TelnetState::Data, Some(TelnetEvent::Data(byte))),
+            (TelnetState::Iac, IAC) => (TelnetState::Data, Some(TelnetEvent::Data(IAC))),
+            (TelnetState::Iac, WILL | WONT | DO | DONT) => (TelnetState::Negotiation(byte), None),
+            (TelnetState::Iac, SB) => (TelnetState::Subnegotiation(Vec::new()), None),
+            (TelnetState::Iac, _) => (TelnetState::Data, None),
+            (TelnetState::Negotiation(command), _) => (
+                TelnetState::Data,
+                Some(TelnetEvent::Negotiation(command, byte)),
+            ),
+            (TelnetState::Subnegotiation(data), IAC) => {
+                (TelnetState::SubnegotiationIac(data), None)
+            }
+            (TelnetState::Subnegotiation(mut data), _) => {
+                data.push(byte);
+                (TelnetState::Subnegotiation(data), None)
+            }
+            (TelnetState::SubnegotiationIac(data), SE) => {
+                (TelnetState::Data, Some(TelnetEvent::Subnegotiation(data)))
+            }
+            (TelnetState::SubnegotiationIac(mut data), _) => {
+                data.push(byte);
+                (TelnetState::Subnegotiation(data), None)
+            }
+        };
+        self.state = state;
+        event
+    }
+}
+
+fn telnet_escape(data: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(data.len());
+    for byte in data {
+        res.push(*byte);
+        if *byte == IAC {
+            res.push(IAC);
+        }
+    }
+    res
+}
+
+fn com_port_command(command: u8, value: &[u8]) -> Vec<u8> {
+    let mut res = vec![IAC, SB, OPT_COM_PORT, command];
+    res.extend(telnet_escape(value));
+    res.extend([IAC, SE]);
+    res
+}
+
+/// Modem control lines of the remote serial port
+struct ComPortLines<'a> {
+    stream: &'a mut TcpStream,
+    protocol: NetworkProtocol,
+}
+
+impl ComPortLines<'_> {
+    fn set_control(&mut self, value: u8) -> Result<()> {
+        if self.protocol != NetworkProtocol::Rfc2217 {
+            return Err(Error::IOError(std::io::Error::new(
+                ErrorKind::Unsupported,
+                "raw TCP connection can't control reset lines, use RFC 2217",
+            )));
+        }
+        self.stream
+            .write_all(&com_port_command(SET_CONTROL, &[value]))?;
+        Ok(())
+    }
+}
+
+impl ControlLines for ComPortLines<'_> {
+    fn set_rts(&mut self, level: bool) -> Result<()> {
+        self.set_control(if level {
+            CONTROL_RTS_ON
+        } else {
+            CONTROL_RTS_OFF
+        })
+    }
+
+    fn set_dtr(&mut self, level: bool) -> Result<()> {
+        self.set_control(if level {
+            CONTROL_DTR_ON
+        } else {
+            CONTROL_DTR_OFF
+        })
+    }
+}
+
+#[derive(gset::Getset)]
+pub struct TcpTransport {
+    stream: TcpStream,
+    protocol: NetworkProtocol,
+    decoder: TelnetDecoder,
+    rx: VecDeque<u8>,
+    timeout: Duration,
+
+    #[getset(set, vis = "pub")]
+    reset_circuit: Box<dyn Reset>,
+
+    #[getset(get_copy, vis = "pub")]
+    #[getset(set, vis = "pub")]
+    reset_invert: bool,
+}
+
+impl TcpTransport {
+    pub fn new(address: &str, protocol: NetworkProtocol) -> Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+
+        let mut transport = Self {
+            stream,
+            protocol,
+            decoder: TelnetDecoder::default(),
+            rx: VecDeque::new(),
+            timeout: Duration::from_millis(200),
+            reset_circuit: Box::new(LineReset::new(ResetType::Rts)),
+            reset_invert: false,
+        };
+
+        if protocol == NetworkProtocol::Rfc2217 {
+            let mut setup = Vec::new();
+            for option in [OPT_BINARY, OPT_SGA] {
+                setup.extend([IAC, WILL, option, IAC, DO, option]);
+            }
+            setup.extend([IAC, WILL, OPT_COM_PORT]);
+            setup.extend(com_port_command(SET_BAUDRATE, &BAUD_RATE.to_be_bytes()));
+            setup.extend(com_port_command(SET_DATASIZE, &[8]));
+            setup.extend(com_port_command(SET_PARITY, &[1]));
+            setup.extend(com_port_command(SET_STOPSIZE, &[1]));
+            setup.extend(com_port_command(SET_CONTROL, &[CONTROL_NO_FLOW]));
+            setup.extend(com_port_command(SET_CONTROL, &[CONTROL_DTR_OFF]));
+            transport.stream.write_all(&setup)?;
+        }
+
+        log::debug!(
+            "Connected to {} using {:?} protocol with baud rate {} and timeout {:?}",
+            address,
+            protocol,
+            BAUD_RATE,
+            transport.timeout,
+        );
+
+        Ok(transport)
+    }
+
+    fn decode(&mut self, data: &[u8]) -> Result<()> {
+        if self.protocol == NetworkProtocol::Raw {
+            self.rx.extend(data);
+            return Ok(());
+        }
+        let mut reply = Vec::new();
+        for byte in data {
+            match self.decoder.push(*byte) {
+                Some(TelnetEvent::Data(byte)) => self.rx.push_back(byte),
+                Some(TelnetEvent::Negotiation(command, option)) => {
+                    let supported = matches!(option, OPT_BINARY | OPT_SGA | OPT_COM_PORT);
+                    match command {
+                        DO if !supported => reply.extend([IAC, WONT, option]),
+                        WILL if !supported => reply.extend([IAC, DONT, option]),
+                        _ => {}
+                    }
+                }
+                Some(TelnetEvent::Subnegotiation(data)) => {
+                    log::trace!("Server subnegotiation {data:02X?}");
+                }
+                None => {}
+            }
+        }
+        if !reply.is_empty() {
+            self.stream.write_all(&reply)?;
+        }
+        Ok(())
+    }
+
+    fn discard_input(&mut self) -> Result<()> {
+        let mut buf = [0; 1024];
+        self.stream.set_nonblocking(true)?;
+        let res = loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => break Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+                Ok(len) => {
+                    if let Err(err) = self.decode(&buf[..len]) {
+                        break Err(err);
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break Ok(()),
+                Err(err) => break Err(err.into()),
+            }
+        };
+        self.stream.set_nonblocking(false)?;
+        self.rx.clear();
+        res
+    }
+
+    fn receive(&mut self, data: &mut [u8]) -> std::io::Result<()> {
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = [0; 1024];
+        while self.rx.len() < data.len() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(ErrorKind::TimedOut.into());
+            }
+            self.stream.set_read_timeout(Some(remaining))?;
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(len) => self.decode(&buf[..len]).map_err(|err| match err {
+                    Error::IOError(err) => err,
+                    err => std::io::Error::other(err),
+                })?,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    return Err(ErrorKind::TimedOut.into());
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        for byte in data.iter_mut() {
+            *byte = self.rx.pop_front().unwrap_or_default();
+        }
+        Ok(())
+    }
+}
+
+impl Transport for TcpTransport {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        log::trace!("Writing {data:02X?}");
+        self.discard_input()?;
+        match self.protocol {
+            NetworkProtocol::Raw => self.stream.write_all(data)?,
+            NetworkProtocol::Rfc2217 => self.stream.write_all(&telnet_escape(data))?,
+        }
+        let mut res = vec![0; data.len()];
+        self.receive(&mut res).map_err(Error::WriteReadFailed)?;
+        check_echo(data, &res)?;
+        log::trace!("Written {} bytes", data.len());
+        Ok(())
+    }
+
+    fn read(&mut self, data: &mut [u8]) -> Result<()> {
+        log::trace!("Reading {} bytes", data.len());
+        self.receive(data)?;
+        log::trace!("Read {data:02X?}");
+        Ok(())
+    }
+
+    fn set_reset(&mut self, mut level: bool) -> Result<()> {
+        log::trace!("Setting reset to {level}");
+        if self.reset_invert {
+            level = !level;
+        }
+        let mut lines = ComPortLines {
+            stream: &mut self.stream,
+            protocol: self.protocol,
+        };
+        self.reset_circuit.set_reset(&mut lines, level)?;
+        log::trace!("Set reset to {level}");
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, value: Duration) -> Result<()> {
+        self.timeout = value;
+        Ok(())
+    }
+
+    fn timeout(&self) -> Result<Duration> {
+        Ok(self.timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{flasher::Flasher, simulator::Simulator};
+    use std::net::TcpListener;
+
+    // Minimal ser2net stand-in: echoes the line and forwards it to a
+    // simulated chip, with RTS wired to chip reset
+    fn spawn_server(sim: Simulator, protocol: NetworkProtocol) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut sim = sim;
+            let mut decoder = TelnetDecoder::default();
+            let mut buf = [0; 1024];
+            while let Ok(len @ 1..) = stream.read(&mut buf) {
+                let mut line = Vec::new();
+                let mut reply = Vec::new();
+                for byte in &buf[..len] {
+                    if protocol == NetworkProtocol::Raw {
+                        line.push(*byte);
+                        continue;
+                    }
+                    match decoder.push(*byte) {
+                        Some(TelnetEvent::Data(byte)) => line.push(byte),
+                        Some(TelnetEvent::Subnegotiation(data)) => {
+                            if let [OPT_COM_PORT, SET_CONTROL, value] = data[..] {
+                                match value {
+                                    CONTROL_RTS_ON => sim.set_reset(true).unwrap(),
+                                    CONTROL_RTS_OFF => sim.set_reset(false).unwrap(),
+                                    _ => {}
+                                }
+                            }
+                            let mut response = data;
+                            response[1] += 100;
+                            reply.extend(com_port_command(response[1], &response[2..]));
+                        }
+                        _ => {}
+                    }
+                }
+                let mut output = line.clone();
+                sim.write(&line).unwrap();
+                let mut byte = [0];
+                while sim.read(&mut byte).is_ok() {
+                    output.push(byte[0]);
+                }
+                match protocol {
+                    NetworkProtocol::Raw => reply.extend(output),
+                    NetworkProtocol::Rfc2217 => reply.extend(telnet_escape(&output)),
+                }
+                stream.write_all(&reply).unwrap();
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn test_telnet_decoder() {
+        let mut decoder = TelnetDecoder::default();
+        let events: Vec<_> = [
+            0x01,
+            IAC,
+            IAC,
+            IAC,
+            DO,
+            OPT_BINARY,
+            IAC,
+            SB,
+            OPT_COM_PORT,
+            101,
+            IAC,
+            IAC,
+            IAC,
+            SE,
+            0x02,
+        ]
+        .into_iter()
+        .filter_map(|x| decoder.push(x))
+        .collect();
+        assert_eq!(
+            events,
+            [
+                TelnetEvent::Data(0x01),
+                TelnetEvent::Data(IAC),
+                TelnetEvent::Negotiation(DO, OPT_BINARY),
+                TelnetEvent::Subnegotiation(vec![OPT_COM_PORT, 101, IAC]),
+                TelnetEvent::Data(0x02),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rfc2217() {
+        let sim = Simulator::new(0x6212, 0x1000, 0x20, 0x100);
+        sim.set_flash(0, &[0xFF, 0x12]);
+        let address = spawn_server(sim, NetworkProtocol::Rfc2217);
+        let transport = TcpTransport::new(&address, NetworkProtocol::Rfc2217).unwrap();
+        let mut flasher = Flasher::new(Box::new(transport));
+        flasher.set_reset_duration_ms(0);
+        assert_eq!(flasher.connect().unwrap(), 0x6212);
+        let mut data = [0; 2];
+        flasher.read_flash(0, &mut data, &|_| {}).unwrap();
+        assert_eq!(data, [0xFF, 0x12]);
+    }
+
+    #[test]
+    fn test_raw() {
+        let sim = Simulator::new(0x6212, 0x1000, 0x20, 0x100);
+        let address = spawn_server(sim, NetworkProtocol::Raw);
+        let transport = TcpTransport::new(&address, NetworkProtocol::Raw).unwrap();
+        let mut flasher = Flasher::new(Box::new(transport));
+        flasher.set_final_reset(false);
+        assert!(flasher.reset().is_err());
+        assert_eq!(flasher.connect_manual().unwrap(), 0x6212);
+    }
+}
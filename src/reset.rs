@@ -0,0 +1,248 @@
+use crate::error::{Error, Result};
+
+/// Modem control lines of a transport
+pub trait ControlLines {
+    fn set_rts(&mut self, level: bool) -> Result<()>;
+    fn set_dtr(&mut self, level: bool) -> Result<()>;
+}
+
+/// Reset circuit driver
+///
+/// `level` is `true` while the chip should be held in reset. Transports
+/// pass their own control lines, so circuits wired to RTS/DTR can use them.
+pub trait Reset {
+    fn set_reset(&mut self, lines: &mut dyn ControlLines, level: bool) -> Result<()>;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ResetType {
+    Rts,
+    Dtr,
+    /// Both lines driven together, like auto-reset circuits expect
+    RtsDtr,
+}
+
+/// Reset driven by transport control lines
+pub struct LineReset {
+    reset_type: ResetType,
+}
+
+impl LineReset {
+    pub fn new(reset_type: ResetType) -> Self {
+        Self { reset_type }
+    }
+}
+
+impl Reset for LineReset {
+    fn set_reset(&mut self, lines: &mut dyn ControlLines, level: bool) -> Result<()> {
+        match self.reset_type {
+            ResetType::Rts => lines.set_rts(level),
+            ResetType::Dtr => lines.set_dtr(level),
+            ResetType::RtsDtr => {
+                lines.set_rts(level)?;
+                lines.set_dtr(level)
+            }
+        }
+    }
+}
+
+/// Reset driven by an external program
+///
+/// The program is run with "1" appended to its arguments to assert reset and
+/// "0" to release it, and must exit successfully.
+pub struct CommandReset {
+    program: String,
+    args: Vec<String>,
+}
+
+impl CommandReset {
+    pub fn new(command: &str) -> Self {
+        let mut words = command.split_whitespace().map(String::from);
+        Self {
+            program: words.next().unwrap_or_default(),
+            args: words.collect(),
+        }
+    }
+}
+
+impl Reset for CommandReset {
+    fn set_reset(&mut self, _lines: &mut dyn ControlLines, level: bool) -> Result<()> {
+        let status = std::process::Command::new(&self.program)
+            .args(&self.args)
+            .arg(if level { "1" } else { "0" })
+            .status()?;
+        if !status.success() {
+            return Err(Error::ResetCommandFailed(self.program.clone(), status));
+        }
+        Ok(())
+    }
+}
+
+/// Reset driven by a GPIO line through the Linux gpiochip character device
+#[cfg(target_os = "linux")]
+pub struct GpioReset {
+    line: std::os::fd::OwnedFd,
+}
+
+#[cfg(target_os = "linux")]
+mod gpio {
+    // Linux GPIO character device uAPI v2, see include/uapi/linux/gpio.h
+
+    pub const GPIO_V2_LINE_FLAG_OUTPUT: u64 = 1 << 3;
+    pub const GPIO_V2_GET_LINE_IOCTL: u32 = 0xC250_B407;
+    pub const GPIO_V2_LINE_SET_VALUES_IOCTL: u32 = 0xC010_B40F;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    pub struct LineConfigAttribute {
+        pub id: u32,
+        pub padding: u32,
+        pub value: u64,
+        pub mask: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct LineConfig {
+        pub flags: u64,
+        pub num_attrs: u32,
+        pub padding: [u32; 5],
+        pub attrs: [LineConfigAttribute; 10],
+    }
+
+    #[repr(C)]
+    pub struct LineRequest {
+        pub offsets: [u32; 64],
+        pub consumer: [u8; 32],
+        pub config: LineConfig,
+        pub num_lines: u32,
+        pub event_buffer_size: u32,
+        pub padding: [u32; 5],
+        pub fd: i32,
+    }
+
+    #[repr(C)]
+    pub struct LineValues {
+        pub bits: u64,
+        pub mask: u64,
+    }
+
+    const _: () = assert!(std::mem::size_of::<LineRequest>() == 592);
+    const _: () = assert!(std::mem::size_of::<LineValues>() == 16);
+}
+
+#[cfg(target_os = "linux")]
+impl GpioReset {
+    pub fn new(chip_path: &str, line: u32) -> Result<Self> {
+        use std::os::fd::{AsRawFd, FromRawFd};
+
+        let chip = std::fs::File::open(chip_path)?;
+        let mut request = gpio::LineRequest {
+            offsets: [0; 64],
+            consumer: [0; 32],
+            config: gpio::LineConfig {
+                flags: gpio::GPIO_V2_LINE_FLAG_OUTPUT,
+                ..Default::default()
+            },
+            num_lines: 1,
+            event_buffer_size: 0,
+            padding: [0; 5],
+            fd: -1,
+        };
+        request.offsets[0] = line;
+        request.consumer[..8].copy_from_slice(b"sn8flash");
+
+        // SAFETY: request matches the kernel struct layout and outlives the call
+        let res = unsafe {
+            libc::ioctl(
+                chip.as_raw_fd(),
+                gpio::GPIO_V2_GET_LINE_IOCTL as _,
+                &mut request,
+            )
+        };
+        if res < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        log::debug!("Requested GPIO line {line} of {chip_path}");
+
+        // SAFETY: the kernel returned a fresh file descriptor we now own
+        let line = unsafe { std::os::fd::OwnedFd::from_raw_fd(request.fd) };
+        Ok(Self { line })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Reset for GpioReset {
+    fn set_reset(&mut self, _lines: &mut dyn ControlLines, level: bool) -> Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let mut values = gpio::LineValues {
+            bits: level as u64,
+            mask: 1,
+        };
+        // SAFETY: values matches the kernel struct layout and outlives the call
+        let res = unsafe {
+            libc::ioctl(
+                self.line.as_raw_fd(),
+                gpio::GPIO_V2_LINE_SET_VALUES_IOCTL as _,
+                &mut values,
+            )
+        };
+        if res < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Lines {
+        rts: bool,
+        dtr: bool,
+    }
+
+    impl ControlLines for Lines {
+        fn set_rts(&mut self, level: bool) -> Result<()> {
+            self.rts = level;
+            Ok(())
+        }
+
+        fn set_dtr(&mut self, level: bool) -> Result<()> {
+            self.dtr = level;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_line_reset() {
+        let mut lines = Lines::default();
+        LineReset::new(ResetType::RtsDtr)
+            .set_reset(&mut lines, true)
+            .unwrap();
+        assert!(lines.rts && lines.dtr);
+        LineReset::new(ResetType::Dtr)
+            .set_reset(&mut lines, false)
+            .unwrap();
+        assert!(lines.rts && !lines.dtr);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_reset() {
+        let mut lines = Lines::default();
+        assert!(
+            CommandReset::new("test 1 -eq")
+                .set_reset(&mut lines, true)
+                .is_ok()
+        );
+        assert!(matches!(
+            CommandReset::new("test 1 -eq").set_reset(&mut lines, false),
+            Err(Error::ResetCommandFailed(..))
+        ));
+    }
+}
@@ -0,0 +1,50 @@
+/// Names of known SFRs: standard 8051 ones, plus SN8F5xxx extensions
+pub const SFR_NAMES: [(u8, &str); 33] = [
+    (0x80, "P0"),
+    (0x81, "SP"),
+    (0x82, "DPL"),
+    (0x83, "DPH"),
+    (0x84, "DPL1"),
+    (0x85, "DPH1"),
+    (0x87, "PCON"),
+    (0x88, "TCON"),
+    (0x89, "TMOD"),
+    (0x8A, "TL0"),
+    (0x8B, "TL1"),
+    (0x8C, "TH0"),
+    (0x8D, "TH1"),
+    (0x8E, "CKON"),
+    (0x90, "P1"),
+    (0x92, "DPS"),
+    (0x93, "DPC"),
+    (0x94, "PECMD"),
+    (0x95, "PEROML"),
+    (0x96, "PEROMH"),
+    (0x97, "PERAM"),
+    (0x98, "SCON"),
+    (0x99, "SBUF"),
+    (0xA0, "P2"),
+    (0xA8, "IE"),
+    (0xB0, "P3"),
+    (0xB8, "IP"),
+    (0xC8, "T2CON"),
+    (0xCC, "TL2"),
+    (0xCD, "TH2"),
+    (0xD0, "PSW"),
+    (0xE0, "ACC"),
+    (0xF0, "B"),
+];
+
+pub fn sfr_name(address: u8) -> Option<&'static str> {
+    SFR_NAMES
+        .iter()
+        .find(|(x, _)| *x == address)
+        .map(|(_, name)| *name)
+}
+
+pub fn sfr_address(name: &str) -> Option<u8> {
+    SFR_NAMES
+        .iter()
+        .find(|(_, x)| x.eq_ignore_ascii_case(name))
+        .map(|(address, _)| *address)
+}
@@ -0,0 +1,833 @@
+use crate::{
+    error::{Error, Result},
+    flasher::HANDSHAKE,
+    transport::Transport,
+};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+/// Write check status reported when the ISP engine is idle
+const WRITE_FINISHED: u16 = 0x015D;
+
+/// Byte times between the end of a request and the start of its reply
+///
+/// Not measured on real chips, which are assumed to answer right away.
+const REPLY_DELAY: usize = 0;
+
+/// Simulated SN8F5xxx chip sitting behind its programming bootloader
+///
+/// Implements [`Transport`], so it can be handed to a `Flasher` instead of
+/// a serial port. Clones share the same chip, which allows inspecting and
+/// preparing chip state while the flasher owns the transport.
+#[derive(Clone)]
+pub struct Simulator {
+    chip: Arc<Mutex<SimulatedChip>>,
+    timeout: Duration,
+}
+
+struct SimulatedChip {
+    // Persistent state
+    chip_id: u32,
+    page_size: usize,
+    erased_value: u8,
+    protected: bool,
+    banks: [Vec<u8>; 2],
+
+    // Core state
+    iram: [u8; 256],
+    sfr: [u8; 128],
+    xram: Vec<u8>,
+    rom_bank: u8,
+
+    // Debug interface state
+    in_reset: bool,
+    connected: bool,
+    rx: Vec<u8>,
+    tx: VecDeque<u8>,
+    select: u8,
+    mode: u8,
+    stream: bool,
+    data: u32,
+    instruction: [u8; 3],
+}
+
+impl Simulator {
+    pub fn new(chip_id: u32, flash_size: usize, page_size: usize, boot_size: usize) -> Self {
+        let erased_value = 0xFF;
+        let chip = SimulatedChip {
+            chip_id,
+            page_size,
+            erased_value,
+            protected: false,
+            banks: [
+                vec![erased_value; flash_size],
+                vec![erased_value; boot_size],
+            ],
+            iram: [0; 256],
+            sfr: [0; 128],
+            xram: vec![0; 0x10000],
+            rom_bank: 0,
+            in_reset: false,
+            connected: false,
+            rx: Vec::new(),
+            tx: VecDeque::new(),
+            select: 0,
+            mode: 0,
+            stream: false,
+            data: 0,
+            instruction: [0; 3],
+        };
+        Self {
+            chip: Arc::new(Mutex::new(chip)),
+            timeout: Duration::from_millis(50),
+        }
+    }
+
+    fn chip(&self) -> MutexGuard<'_, SimulatedChip> {
+        self.chip.lock().expect("Simulator state poisoned")
+    }
+
+    pub fn flash(&self) -> Vec<u8> {
+        self.chip().banks[0].clone()
+    }
+
+    pub fn set_flash(&self, offset: usize, data: &[u8]) {
+        self.chip().banks[0][offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    pub fn boot(&self) -> Vec<u8> {
+        self.chip().banks[1].clone()
+    }
+
+    pub fn set_boot(&self, offset: usize, data: &[u8]) {
+        self.chip().banks[1][offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    pub fn iram(&self) -> [u8; 256] {
+        self.chip().iram
+    }
+
+    pub fn sfr(&self, address: u8) -> u8 {
+        self.chip().sfr[(address & 0x7F) as usize]
+    }
+
+    pub fn set_iram(&self, address: u8, data: &[u8]) {
+        let address = address as usize;
+        self.chip().iram[address..address + data.len()].copy_from_slice(data);
+    }
+
+    pub fn set_sfr(&self, address: u8, value: u8) {
+        self.chip().sfr[(address & 0x7F) as usize] = value;
+    }
+
+    pub fn xram(&self, address: u16) -> u8 {
+        self.chip().xram[address as usize]
+    }
+
+    pub fn erased_value(&self) -> u8 {
+        self.chip().erased_value
+    }
+
+    /// Changes erased flash value, wiping both ROM banks
+    pub fn set_erased_value(&self, value: u8) {
+        let mut chip = self.chip();
+        chip.erased_value = value;
+        for bank in chip.banks.iter_mut() {
+            bank.fill(value);
+        }
+    }
+
+    pub fn is_protected(&self) -> bool {
+        self.chip().protected
+    }
+
+    /// Makes main flash read as zeroes, like read protection does
+    pub fn set_protected(&self, value: bool) {
+        self.chip().protected = value;
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.chip().connected
+    }
+}
+
+impl Transport for Simulator {
+    /// Sends bytes one byte time apart on the single-wire line
+    ///
+    /// Replies start [`REPLY_DELAY`] byte times after their request. When
+    /// that is still within this write, both sides drive the line at once:
+    /// the chip and the host echo see the wired-AND of both bytes. Between
+    /// writes the line is assumed to be idle long enough for replies.
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        log::trace!("Simulator received {data:02X?}");
+        let mut chip = self.chip();
+        if chip.in_reset {
+            return Ok(());
+        }
+
+        // Byte time at which each pending reply byte in tx goes on the line
+        let mut replies = VecDeque::new();
+        let mut chip_busy_until = 0;
+        let mut mismatch = None;
+        for (time, &sent) in data.iter().enumerate() {
+            while replies.front().is_some_and(|&(start, _)| start < time) {
+                replies.pop_front();
+            }
+            let mut byte = sent;
+            if let Some(&(_, index)) = replies.front().filter(|&&(start, _)| start == time) {
+                byte &= chip.tx[index];
+                chip.tx[index] = byte;
+                if byte != sent {
+                    mismatch.get_or_insert(Error::WriteReadMismatch(time, sent, byte));
+                }
+            }
+
+            let tx_len = chip.tx.len();
+            chip.rx.push(byte);
+            chip.process();
+            chip_busy_until = chip_busy_until.max(time + 1 + REPLY_DELAY);
+            for index in tx_len..chip.tx.len() {
+                replies.push_back((chip_busy_until, index));
+                chip_busy_until += 1;
+            }
+        }
+        mismatch.map_or(Ok(()), Err)
+    }
+
+    fn read(&mut self, data: &mut [u8]) -> Result<()> {
+        let mut chip = self.chip();
+        if chip.tx.len() < data.len() {
+            chip.tx.clear();
+            return Err(Error::IOError(std::io::ErrorKind::TimedOut.into()));
+        }
+        for byte in data.iter_mut() {
+            *byte = chip.tx.pop_front().unwrap_or_default();
+        }
+        log::trace!("Simulator sent {data:02X?}");
+        Ok(())
+    }
+
+    fn set_reset(&mut self, level: bool) -> Result<()> {
+        let mut chip = self.chip();
+        chip.in_reset = level;
+        if level {
+            chip.reset();
+        }
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, value: Duration) -> Result<()> {
+        self.timeout = value;
+        Ok(())
+    }
+
+    fn timeout(&self) -> Result<Duration> {
+        Ok(self.timeout)
+    }
+}
+
+impl SimulatedChip {
+    fn reset(&mut self) {
+        self.connected = false;
+        self.iram = [0; 256];
+        self.sfr = [0; 128];
+        self.rom_bank = 0;
+        self.rx.clear();
+        self.tx.clear();
+        self.select = 0;
+        self.mode = 0;
+        self.stream = false;
+        self.data = 0;
+    }
+
+    fn command_len(command: u8) -> Option<usize> {
+        match command {
+            0x08 => Some(HANDSHAKE.len()),
+            0x21 | 0x2A | 0x2B | 0x88 | 0x8B | 0xA0 => Some(2),
+            0x48 => Some(3),
+            0x4B => Some(4),
+            0x58 => Some(5),
+            _ => None,
+        }
+    }
+
+    fn process(&mut self) {
+        loop {
+            match self.rx.as_slice() {
+                [] | [0x55] => break,
+                [0x55, command, ..] => {
+                    let Some(len) = Self::command_len(*command) else {
+                        log::warn!("Simulator dropped unknown command {command:#04X}");
+                        self.rx.drain(..2);
+                        continue;
+                    };
+                    if self.rx.len() < len {
+                        break;
+                    }
+                    let frame: Vec<u8> = self.rx.drain(..len).collect();
+                    self.execute_command(&frame);
+                }
+                [other, ..] => {
+                    log::warn!("Simulator dropped stray byte {other:#04X}");
+                    self.rx.remove(0);
+                }
+            }
+        }
+    }
+
+    fn execute_command(&mut self, frame: &[u8]) {
+        if !self.connected {
+            if frame == HANDSHAKE {
+                self.connected = true;
+                self.tx.extend([0xFF; 4]);
+            }
+            return;
+        }
+        match frame[1..] {
+            [0x21] => self.data = self.chip_id,
+            [0x2A] => self.stream = true,
+            [0x2B] => self.stream = false,
+            [0x88] => {
+                let value = if self.stream && self.mode == 0x04 {
+                    let dptr = self.dptr();
+                    self.set_dptr(dptr.wrapping_add(1));
+                    self.read_code(dptr)
+                } else if self.select == 0x83 {
+                    self.acc()
+                } else {
+                    0x00
+                };
+                self.tx.push_back(value);
+            }
+            [0x8B] => {
+                let value = if self.select == 0x81 {
+                    WRITE_FINISHED
+                } else {
+                    0x0000
+                };
+                self.tx.extend(value.to_le_bytes());
+            }
+            [0xA0] => self.tx.extend(self.data.to_le_bytes()),
+            [0x48, value] => {
+                if self.select == 0x88 {
+                    self.mode = value;
+                    if value == 0x05 {
+                        let value = self.read_xram(self.dptr());
+                        self.set_acc(value);
+                    }
+                    self.select = 0x00;
+                } else {
+                    self.select = value;
+                }
+            }
+            [0x4B, 0x57, 0x01] if self.select == 0x80 => self.execute_instruction(),
+            [0x4B, ..] => {}
+            [0x58, arg2, arg1, opcode] if self.select == 0x86 => {
+                self.instruction = [opcode, arg1, arg2];
+            }
+            _ => log::warn!("Simulator ignored command {frame:02X?}"),
+        }
+    }
+
+    fn execute_instruction(&mut self) {
+        let [opcode, arg1, arg2] = self.instruction;
+        // Register Rn of the current bank, for opcodes encoding it
+        let rn = self.r0_address() + (opcode & 0x07);
+        match opcode {
+            0x00 => {}                                               // NOP
+            0x04 => self.set_acc(self.acc().wrapping_add(1)),        // INC A
+            0x14 => self.set_acc(self.acc().wrapping_sub(1)),        // DEC A
+            0x24 => self.set_acc(self.acc().wrapping_add(arg1)),     // ADD A, #data
+            0x44 => self.set_acc(self.acc() | arg1),                 // ORL A, #data
+            0x54 => self.set_acc(self.acc() & arg1),                 // ANL A, #data
+            0x64 => self.set_acc(self.acc() ^ arg1),                 // XRL A, #data
+            0x74 => self.set_acc(arg1),                              // MOV A, #data
+            0x75 => self.write_direct(arg1, arg2),                   // MOV direct, #data
+            0x78..=0x7F => self.write_direct(rn, arg1),              // MOV Rn, #data
+            0x90 => self.set_dptr(u16::from_be_bytes([arg1, arg2])), // MOV DPTR, #data16
+            0xA3 => self.set_dptr(self.dptr().wrapping_add(1)),      // INC DPTR
+            0xC4 => self.set_acc(self.acc().rotate_left(4)),         // SWAP A
+            0xE4 => self.set_acc(0x00),                              // CLR A
+            0xF4 => self.set_acc(!self.acc()),                       // CPL A
+            0x05 | 0x43 | 0x53 | 0x85 => {
+                // INC direct, ORL direct, #data, ANL direct, #data, MOV direct, direct
+                let (address, value) = match opcode {
+                    0x05 => (arg1, self.read_direct(arg1).wrapping_add(1)),
+                    0x43 => (arg1, self.read_direct(arg1) | arg2),
+                    0x53 => (arg1, self.read_direct(arg1) & arg2),
+                    _ => (arg2, self.read_direct(arg1)),
+                };
+                self.write_direct(address, value);
+            }
+            0xC2 | 0xD2 => {
+                // CLR bit, SETB bit
+                let (address, mask) = Self::bit_address(arg1);
+                let value = self.read_direct(address);
+                let value = if opcode == 0xD2 {
+                    value | mask
+                } else {
+                    value & !mask
+                };
+                self.write_direct(address, value);
+            }
+            0xC5 => {
+                // XCH A, direct
+                let value = self.read_direct(arg1);
+                self.write_direct(arg1, self.acc());
+                self.set_acc(value);
+            }
+            0xE0 => {
+                // MOVX A, @DPTR
+                let value = self.read_xram(self.dptr());
+                self.set_acc(value);
+            }
+            0xE5 => {
+                // MOV A, direct
+                let value = self.read_direct(arg1);
+                self.set_acc(value);
+            }
+            0xE6 => {
+                // MOV A, @R0
+                let value = self.iram[self.read_direct(self.r0_address()) as usize];
+                self.set_acc(value);
+            }
+            0xE8..=0xEF => {
+                // MOV A, Rn
+                let value = self.read_direct(rn);
+                self.set_acc(value);
+            }
+            0xF0 => self.write_xram(self.dptr(), self.acc()), // MOVX @DPTR, A
+            0xF5 => self.write_direct(arg1, self.acc()),      // MOV direct, A
+            0xF6 => {
+                // MOV @R0, A
+                let address = self.read_direct(self.r0_address());
+                self.iram[address as usize] = self.acc();
+            }
+            0xF8..=0xFF => self.write_direct(rn, self.acc()), // MOV Rn, A
+            _ => log::warn!("Simulator ignored opcode {:02X?}", self.instruction),
+        }
+    }
+
+    fn read_direct(&self, address: u8) -> u8 {
+        if address < 0x80 {
+            self.iram[address as usize]
+        } else {
+            self.sfr[(address & 0x7F) as usize]
+        }
+    }
+
+    fn write_direct(&mut self, address: u8, value: u8) {
+        if address < 0x80 {
+            self.iram[address as usize] = value;
+        } else {
+            self.sfr[(address & 0x7F) as usize] = value;
+            if address == 0x94 {
+                self.execute_isp(value);
+            }
+        }
+    }
+
+    /// Byte address and mask of a bit address
+    fn bit_address(bit: u8) -> (u8, u8) {
+        let address = if bit < 0x80 {
+            0x20 + bit / 8
+        } else {
+            bit & 0xF8
+        };
+        (address, 1 << (bit & 0x07))
+    }
+
+    fn r0_address(&self) -> u8 {
+        self.read_direct(0xD0) & 0x18
+    }
+
+    fn acc(&self) -> u8 {
+        self.read_direct(0xE0)
+    }
+
+    fn set_acc(&mut self, value: u8) {
+        self.write_direct(0xE0, value);
+    }
+
+    fn dptr(&self) -> u16 {
+        u16::from_le_bytes([self.read_direct(0x82), self.read_direct(0x83)])
+    }
+
+    fn set_dptr(&mut self, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        self.write_direct(0x82, low);
+        self.write_direct(0x83, high);
+    }
+
+    fn read_xram(&self, address: u16) -> u8 {
+        match address {
+            0xFFFC => self.rom_bank,
+            _ => self.xram[address as usize],
+        }
+    }
+
+    fn write_xram(&mut self, address: u16, value: u8) {
+        match address {
+            0xFFFC => self.rom_bank = value,
+            _ => self.xram[address as usize] = value,
+        }
+    }
+
+    fn bank(&mut self) -> &mut Vec<u8> {
+        &mut self.banks[(self.rom_bank & 0x01) as usize]
+    }
+
+    fn read_code(&mut self, address: u16) -> u8 {
+        if self.protected && self.rom_bank & 0x01 == 0 {
+            return 0x00;
+        }
+        let bank = self.bank();
+        bank[address as usize % bank.len()]
+    }
+
+    fn execute_isp(&mut self, command: u8) {
+        let address = u16::from_be_bytes([self.read_direct(0x96), self.read_direct(0x95)]);
+        if address & 0x0F != 0x0A {
+            log::warn!("Simulator ignored ISP command with address {address:#06X}");
+            return;
+        }
+        match command {
+            0x96 => {
+                let erased_value = self.erased_value;
+                self.bank().fill(erased_value);
+                if self.rom_bank & 0x01 == 0 {
+                    self.protected = false;
+                }
+            }
+            0xA5 => {
+                let page_size = self.page_size;
+                let erased_value = self.erased_value;
+                let page = (address as usize & !(page_size - 1)) % self.bank().len();
+                self.bank()[page..page + page_size].fill(erased_value);
+            }
+            0x5A => {
+                let page_size = self.page_size;
+                let ram = self.read_direct(0x97) as usize;
+                let page = (address as usize & !(page_size - 1)) % self.bank().len();
+                let data: Vec<u8> = (0..page_size).map(|i| self.iram[(ram + i) % 256]).collect();
+                let erased_value = self.erased_value;
+                for (cell, byte) in self.bank()[page..page + page_size].iter_mut().zip(data) {
+                    // Programming can only move bits away from the erased state
+                    if erased_value == 0xFF {
+                        *cell &= byte;
+                    } else {
+                        *cell |= byte;
+                    }
+                }
+            }
+            _ => log::warn!("Simulator ignored ISP command {command:#04X}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        firmware::{BootBackup, Firmware},
+        flasher::{BlankCheck, Flasher, ProtectionStatus, RomBank},
+    };
+
+    const CHIP_ID: u32 = 0x6212;
+
+    fn connect() -> (Simulator, Flasher) {
+        let sim = Simulator::new(CHIP_ID, 0x1000, 0x20, 0x100);
+        let mut flasher = Flasher::new(Box::new(sim.clone()));
+        flasher.set_reset_duration_ms(0);
+        flasher.set_connect_delay_us(0);
+        assert_eq!(flasher.connect().unwrap(), CHIP_ID);
+        (sim, flasher)
+    }
+
+    #[test]
+    fn test_connect() {
+        let (sim, flasher) = connect();
+        assert!(sim.is_connected());
+        drop(flasher);
+        assert!(!sim.is_connected());
+    }
+
+    #[test]
+    fn test_read_flash() {
+        let (sim, mut flasher) = connect();
+        sim.set_flash(0x100, &[0x02, 0x01, 0x23]);
+        let mut data = [0; 4];
+        flasher.read_flash(0x0FF, &mut data, &|_| {}).unwrap();
+        assert_eq!(data, [0xFF, 0x02, 0x01, 0x23]);
+
+        flasher.set_has_ckon(false);
+        let mut data = [0; 2];
+        flasher.read_flash(0x101, &mut data, &|_| {}).unwrap();
+        assert_eq!(data, [0x01, 0x23]);
+    }
+
+    #[test]
+    fn test_reply_collision() {
+        let (sim, _flasher) = connect();
+        let mut line = sim.clone();
+        line.write(&[0x55, 0x88]).unwrap();
+        let mut reply = [0xFF];
+        line.read(&mut reply).unwrap();
+        assert_eq!(reply, [0x00]);
+
+        // The reply to the first request is due while the second one is sent
+        assert!(matches!(
+            line.write(&[0x55, 0x88, 0x55, 0x88]),
+            Err(Error::WriteReadMismatch(2, 0x55, 0x00))
+        ));
+    }
+
+    #[test]
+    fn test_erase_write_verify() {
+        let (sim, mut flasher) = connect();
+        sim.set_flash(0x800, &[0x00; 0x10]);
+        flasher.erase_flash().unwrap();
+        assert!(sim.flash().iter().all(|x| *x == 0xFF));
+
+        let raw: Vec<u8> = (0..0x50).collect();
+        let firmware = Firmware::from_raw_bytes(raw.clone(), 0x20, 0x30, 0xFF).unwrap();
+        flasher.write_flash(&firmware, &|_| {}).unwrap();
+        flasher.verify_flash(&firmware, &|_| {}).unwrap();
+        assert_eq!(sim.flash()[0x30..0x80], raw);
+        assert_eq!(sim.flash()[0x20..0x30], [0xFF; 0x10]);
+
+        sim.set_flash(0x40, &[0x00]);
+        sim.set_flash(0x5E, &[0x00; 4]);
+        let Err(Error::VerifyMismatch(report)) = flasher.verify_flash(&firmware, &|_| {}) else {
+            panic!("verify should fail");
+        };
+        assert_eq!(report.bytes(), 5);
+        assert_eq!(report.pages(), 2);
+        assert_eq!(report.ranges().len(), 2);
+        assert_eq!(report.ranges()[0].offset(), 0x40);
+        assert_eq!(report.ranges()[0].actual(), [0x00]);
+        assert_eq!(report.ranges()[1].offset(), 0x5E);
+        assert_eq!(report.ranges()[1].expected(), [0x2E, 0x2F, 0x30, 0x31]);
+        assert_eq!(sim.xram(0xFFFC), 0);
+    }
+
+    #[test]
+    fn test_erase_pages() {
+        let (sim, mut flasher) = connect();
+        sim.set_flash(0, &[0x00; 0x1000]);
+        assert!(matches!(
+            flasher.erase_range(0x30, 0x20, 0x20, 0x1000, &|_| {}),
+            Err(Error::UnverifiedPageErase)
+        ));
+        flasher.set_allow_unverified_page_erase(true);
+        flasher.set_dangerous_allow_write_non_main_bank(true);
+        flasher.set_rom_bank(RomBank::Boot);
+        assert!(matches!(
+            flasher.erase_range(0, 0x20, 0x20, 0x1000, &|_| {}),
+            Err(Error::NonMainBankPageErase)
+        ));
+        flasher.set_rom_bank(RomBank::Main);
+        assert_eq!(sim.flash()[..0x20], [0x00; 0x20]);
+
+        assert!(matches!(
+            flasher.erase_range(0xFF0, 0x20, 0x20, 0x1000, &|_| {}),
+            Err(Error::AddressOutOfRange("Flash", 0xFF0, 0x20))
+        ));
+        flasher
+            .erase_range(0x30, 0x20, 0x20, 0x1000, &|_| {})
+            .unwrap();
+        assert_eq!(sim.flash()[0x00..0x20], [0x00; 0x20]);
+        assert_eq!(sim.flash()[0x20..0x60], [0xFF; 0x40]);
+        assert_eq!(sim.flash()[0x60..0x80], [0x00; 0x20]);
+
+        let firmware = Firmware::from_raw_bytes(vec![0x12; 0x10], 0x20, 0xFE8, 0xFF).unwrap();
+        flasher.erase_firmware_pages(&firmware, &|_| {}).unwrap();
+        flasher.write_flash(&firmware, &|_| {}).unwrap();
+        flasher.verify_flash(&firmware, &|_| {}).unwrap();
+        assert_eq!(sim.flash()[0xFC0..0xFE0], [0x00; 0x20]);
+    }
+
+    #[test]
+    fn test_smart_write() {
+        let (sim, mut flasher) = connect();
+        let mut raw = vec![0x55; 0x80];
+        sim.set_flash(0, &raw);
+        raw[0x45] = 0xAA;
+        sim.set_flash(0x60, &[0x00]);
+        let firmware = Firmware::from_raw_bytes(raw.clone(), 0x20, 0, 0xFF).unwrap();
+
+        flasher.set_smart_write(true);
+        flasher.set_allow_unverified_page_erase(true);
+        let stats = flasher.write_flash(&firmware, &|_| {}).unwrap();
+        assert_eq!((stats.pages_written(), stats.pages_skipped()), (2, 2));
+        assert_eq!(sim.flash()[..0x80], raw);
+    }
+
+    #[test]
+    fn test_blank_check() {
+        let (sim, mut flasher) = connect();
+        let blank_check = |flasher: &mut Flasher| flasher.blank_check(0, 0x1000, 0xFF, &|_| {});
+        assert_eq!(blank_check(&mut flasher).unwrap(), BlankCheck::Blank);
+
+        sim.set_flash(0x10, &[0x00; 0x20]);
+        assert_eq!(
+            blank_check(&mut flasher).unwrap(),
+            BlankCheck::NotBlank(0x20, (0x10..0x20).collect())
+        );
+
+        sim.set_protected(true);
+        assert_eq!(
+            blank_check(&mut flasher).unwrap(),
+            BlankCheck::ReadProtected
+        );
+    }
+
+    #[test]
+    fn test_protection() {
+        let (sim, mut flasher) = connect();
+        let status = |flasher: &mut Flasher| flasher.protection_status().unwrap();
+        assert_eq!(status(&mut flasher), ProtectionStatus::Unprotected);
+
+        sim.set_protected(true);
+        assert_eq!(status(&mut flasher), ProtectionStatus::Unknown);
+
+        flasher.erase_flash().unwrap();
+        assert!(!sim.is_protected());
+        assert_eq!(status(&mut flasher), ProtectionStatus::Unprotected);
+    }
+
+    #[test]
+    fn test_boot_bank() {
+        let (sim, mut flasher) = connect();
+        sim.set_boot(0, &[0x12, 0x34]);
+        flasher.set_rom_bank(RomBank::Boot);
+        let mut data = [0; 2];
+        flasher.read_flash(0, &mut data, &|_| {}).unwrap();
+        assert_eq!(data, [0x12, 0x34]);
+        assert!(matches!(
+            flasher.erase_flash(),
+            Err(Error::NonMainBankErase)
+        ));
+        assert_eq!(sim.xram(0xFFFC), 0);
+
+        sim.set_boot(0x80, &[0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(
+            flasher.unique_id(0x80, 4).unwrap(),
+            [0xDE, 0xAD, 0xBE, 0xEF]
+        );
+        assert_eq!(flasher.rom_bank(), RomBank::Boot);
+    }
+
+    #[test]
+    fn test_boot_backup_restore() {
+        let (sim, mut flasher) = connect();
+        let original: Vec<u8> = (0..0x100).map(|x| x as u8).collect();
+        sim.set_boot(0, &original);
+        let backup = flasher.backup_boot(0x100, &|_| {}).unwrap();
+        assert_eq!(backup.chip_id(), CHIP_ID);
+        assert_eq!(backup.data(), original);
+        assert_eq!(flasher.rom_bank(), RomBank::Main);
+
+        sim.set_boot(0x40, &[0x00; 0x10]);
+        assert!(matches!(
+            flasher.restore_boot(&backup, 0x20, 0xFF, &|_| {}),
+            Err(Error::NonMainBankWrite)
+        ));
+        flasher.set_dangerous_allow_write_non_main_bank(true);
+        assert!(matches!(
+            flasher.restore_boot(
+                &BootBackup::new(0x6300, vec![0; 0x100]),
+                0x20,
+                0xFF,
+                &|_| {}
+            ),
+            Err(Error::BootBackupChipMismatch(0x6300, CHIP_ID))
+        ));
+        flasher.restore_boot(&backup, 0x20, 0xFF, &|_| {}).unwrap();
+        assert_eq!(sim.boot(), original);
+        assert_eq!(flasher.rom_bank(), RomBank::Main);
+    }
+
+    #[test]
+    fn test_debug_access() {
+        let (sim, mut flasher) = connect();
+        sim.set_sfr(0xD0, 0x08); // Register bank 1, R0 at 0x08
+        sim.set_sfr(0xE0, 0x5A);
+        sim.set_iram(0x7E, &[0x11, 0x22, 0x33, 0x44]);
+
+        let mut data = [0; 4];
+        flasher.read_iram(0x7E, &mut data).unwrap();
+        assert_eq!(data, [0x11, 0x22, 0x33, 0x44]);
+        flasher.write_iram(0x07, &[0xA0, 0xA1]).unwrap();
+        flasher.write_iram(0xFF, &[0xB0]).unwrap();
+        assert_eq!(sim.iram()[0x07..0x09], [0xA0, 0xA1]);
+        assert_eq!(sim.iram()[0xFF], 0xB0);
+
+        let mut data = [0; 2];
+        flasher.read_sfr(0xDF, &mut data).unwrap();
+        assert_eq!(data, [0x00, 0x5A]);
+        flasher.write_sfr(0x90, &[0x3C]).unwrap();
+        assert_eq!(sim.sfr(0x90), 0x3C);
+
+        flasher.write_xram(0x0100, &[0xC0, 0xC1]).unwrap();
+        let mut data = [0; 2];
+        flasher.read_xram(0x0100, &mut data).unwrap();
+        assert_eq!(data, [0xC0, 0xC1]);
+
+        // Registers used for access are restored
+        assert_eq!(sim.sfr(0xE0), 0x5A);
+        assert_eq!(sim.sfr(0x82), 0x00);
+        assert_eq!(sim.iram()[0x08], 0xA1);
+        assert!(matches!(
+            flasher.read_sfr(0x70, &mut data),
+            Err(Error::AddressOutOfRange("SFR", 0x70, 2))
+        ));
+        assert!(matches!(
+            flasher.read_iram(0xFF, &mut data),
+            Err(Error::AddressOutOfRange("IRAM", 0xFF, 2))
+        ));
+
+        // Saved bytes are restored even if the access in between fails
+        let res = flasher.with_saved_direct(&[0xE0], |this| {
+            this.write_sfr(0xE0, &[0x00])?;
+            this.read_sfr(0x70, &mut [0])
+        });
+        assert!(matches!(res, Err(Error::AddressOutOfRange("SFR", 0x70, 1))));
+        assert_eq!(sim.sfr(0xE0), 0x5A);
+    }
+
+    #[test]
+    fn test_exec() {
+        let (sim, mut flasher) = connect();
+        let asm = |text| crate::asm::assemble(text).unwrap();
+
+        flasher.exec(&asm("MOV A, #0x12")).unwrap();
+        assert_eq!(flasher.read_acc().unwrap(), 0x12);
+        assert_eq!(flasher.exec_read_acc(&asm("SWAP A")).unwrap(), 0x21);
+        flasher.exec(&asm("MOV R3, A")).unwrap();
+        flasher.exec(&asm("MOV 0x30, 0x03")).unwrap();
+        flasher.exec(&asm("SETB P1.2")).unwrap();
+        flasher.exec(&[0x90, 0x01, 0x00]).unwrap(); // MOV DPTR, #0x0100
+        flasher.exec(&[0xF0]).unwrap(); // MOVX @DPTR, A
+        assert_eq!(sim.iram()[0x30], 0x21);
+        assert_eq!(sim.sfr(0x90), 0x04);
+        assert_eq!(sim.xram(0x0100), 0x21);
+
+        for instruction in [
+            &[0x74][..],
+            &[0xE4, 0x00],
+            &[0xA5],
+            &[],
+            &[0x02, 0x00, 0x00],
+        ] {
+            assert!(matches!(
+                flasher.exec(instruction),
+                Err(Error::InvalidInstruction(..))
+            ));
+        }
+        flasher.exec_any(&[0x22]).unwrap(); // RET
+    }
+}
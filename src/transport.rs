@@ -1,5 +1,14 @@
-use crate::error::{Error, Result};
-use std::time::Duration;
+use crate::{
+    error::{Error, Result},
+    reset::{ControlLines, LineReset, Reset, ResetType},
+};
+use std::{
+    io::ErrorKind,
+    time::{Duration, Instant},
+};
+
+/// Serial port baud rate expected by the chip
+pub(crate) const BAUD_RATE: u32 = 750_000;
 
 pub trait Transport {
     fn write(&mut self, data: &[u8]) -> Result<()>;
@@ -9,19 +18,43 @@ pub trait Transport {
     fn timeout(&self) -> Result<Duration>;
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
-pub enum ResetType {
-    Rts,
-    Dtr,
+/// Compares the echo of written data, reporting the first differing byte
+pub(crate) fn check_echo(data: &[u8], echo: &[u8]) -> Result<()> {
+    match data.iter().zip(echo).position(|(x, y)| x != y) {
+        Some(i) => Err(Error::WriteReadMismatch(i, data[i], echo[i])),
+        None => Ok(()),
+    }
+}
+
+/// Transfer counters, logged when the transport is dropped
+#[derive(Default)]
+struct TransferStats {
+    round_trips: u64,
+    bytes_written: u64,
+    bytes_read: u64,
+    busy: Duration,
+}
+
+impl TransferStats {
+    fn log(&self) {
+        let bytes = self.bytes_written + self.bytes_read;
+        log::debug!(
+            "Serial port: {} round trips, {} bytes written, {} bytes read, {:.0} bytes/s while busy",
+            self.round_trips,
+            self.bytes_written,
+            self.bytes_read,
+            bytes as f64 / self.busy.as_secs_f64().max(f64::EPSILON),
+        );
+    }
 }
 
 #[derive(gset::Getset)]
 pub struct SerialPortTransport {
     port: serial2::SerialPort,
+    stats: TransferStats,
 
-    #[getset(get_copy, vis = "pub")]
     #[getset(set, vis = "pub")]
-    reset_type: ResetType,
+    reset_circuit: Box<dyn Reset>,
 
     #[getset(get_copy, vis = "pub")]
     #[getset(set, vis = "pub")]
@@ -30,7 +63,7 @@ pub struct SerialPortTransport {
 
 impl SerialPortTransport {
     pub fn new(path: &str) -> Result<Self> {
-        let mut port = serial2::SerialPort::open(path, 750_000)?;
+        let mut port = serial2::SerialPort::open(path, BAUD_RATE)?;
         port.set_read_timeout(Duration::from_millis(50))?;
         port.set_dtr(false)?;
 
@@ -47,40 +80,61 @@ impl SerialPortTransport {
 
         Ok(Self {
             port,
-            reset_type: ResetType::Rts,
+            stats: TransferStats::default(),
+            reset_circuit: Box::new(LineReset::new(ResetType::Rts)),
             reset_invert: false,
         })
     }
+
+    /// Reads exactly `data.len()` bytes in as few calls as possible
+    ///
+    /// The deadline allows for the time the bytes take on the line, plus the
+    /// read timeout.
+    fn read_chunked(&mut self, data: &mut [u8]) -> std::io::Result<()> {
+        let line_time = Duration::from_micros(data.len() as u64 * 10_000_000 / BAUD_RATE as u64);
+        let deadline = Instant::now() + line_time + self.port.get_read_timeout()?;
+        let mut pos = 0;
+        while pos < data.len() {
+            match self.port.read(&mut data[pos..]) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(len) => pos += len,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) if err.kind() != ErrorKind::TimedOut => return Err(err),
+                Err(_) => {}
+            }
+            if pos < data.len() && Instant::now() >= deadline {
+                return Err(ErrorKind::TimedOut.into());
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Transport for SerialPortTransport {
     fn write(&mut self, data: &[u8]) -> Result<()> {
         log::trace!("Writing {data:02X?}");
+        let start = Instant::now();
         self.port.discard_buffers()?;
         self.port.write_all(data)?;
         self.port.flush()?;
-        let mut res = [0];
-        let mut mismatch = false;
-        for byte in data {
-            self.port
-                .read_exact(&mut res)
-                .map_err(Error::WriteReadFailed)?;
-            if *byte != res[0] {
-                mismatch = true;
-            }
-        }
-        if mismatch {
-            return Err(Error::WriteReadMismatch);
-        }
+        let mut echo = vec![0; data.len()];
+        let res = self.read_chunked(&mut echo);
+        self.stats.round_trips += 1;
+        self.stats.bytes_written += data.len() as u64;
+        self.stats.busy += start.elapsed();
+        res.map_err(Error::WriteReadFailed)?;
+        check_echo(data, &echo)?;
         log::trace!("Written {} bytes", data.len());
         Ok(())
     }
 
     fn read(&mut self, data: &mut [u8]) -> Result<()> {
         log::trace!("Reading {} bytes", data.len());
-        for i in 0..data.len() {
-            self.port.read_exact(&mut data[i..=i])?;
-        }
+        let start = Instant::now();
+        let res = self.read_chunked(data);
+        self.stats.bytes_read += data.len() as u64;
+        self.stats.busy += start.elapsed();
+        res?;
         log::trace!("Read {data:02X?}");
         Ok(())
     }
@@ -90,14 +144,7 @@ impl Transport for SerialPortTransport {
         if self.reset_invert {
             level = !level;
         }
-        match self.reset_type {
-            ResetType::Rts => {
-                self.port.set_rts(level)?;
-            }
-            ResetType::Dtr => {
-                self.port.set_dtr(level)?;
-            }
-        }
+        self.reset_circuit.set_reset(&mut self.port, level)?;
         log::trace!("Set reset to {level}");
         Ok(())
     }
@@ -110,3 +157,33 @@ impl Transport for SerialPortTransport {
         Ok(self.port.get_read_timeout()?)
     }
 }
+
+impl Drop for SerialPortTransport {
+    fn drop(&mut self) {
+        self.stats.log();
+    }
+}
+
+impl ControlLines for serial2::SerialPort {
+    fn set_rts(&mut self, level: bool) -> Result<()> {
+        Ok(serial2::SerialPort::set_rts(self, level)?)
+    }
+
+    fn set_dtr(&mut self, level: bool) -> Result<()> {
+        Ok(serial2::SerialPort::set_dtr(self, level)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_echo() {
+        assert!(check_echo(&[0x55, 0x88], &[0x55, 0x88]).is_ok());
+        assert!(matches!(
+            check_echo(&[0x55, 0x88, 0x55], &[0x55, 0x88, 0x54]),
+            Err(Error::WriteReadMismatch(2, 0x55, 0x54))
+        ));
+    }
+}
@@ -0,0 +1,85 @@
+/// Contiguous run of bytes that differ from firmware
+#[derive(gset::Getset, serde::Serialize, Clone, PartialEq, Eq, Debug)]
+pub struct MismatchRange {
+    #[getset(get_copy, vis = "pub")]
+    offset: usize,
+    #[getset(get_deref, vis = "pub")]
+    expected: Vec<u8>,
+    #[getset(get_deref, vis = "pub")]
+    actual: Vec<u8>,
+}
+
+impl MismatchRange {
+    pub fn len(&self) -> usize {
+        self.expected.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn end(&self) -> usize {
+        self.offset + self.len()
+    }
+}
+
+#[derive(gset::Getset, serde::Serialize, Clone, PartialEq, Eq, Debug, Default)]
+pub struct VerifyReport {
+    #[getset(get_deref, vis = "pub")]
+    ranges: Vec<MismatchRange>,
+    /// Total count of mismatched bytes
+    #[getset(get_copy, vis = "pub")]
+    bytes: usize,
+    /// Count of pages with at least one mismatched byte
+    #[getset(get_copy, vis = "pub")]
+    pages: usize,
+}
+
+impl VerifyReport {
+    pub(crate) fn add(&mut self, offset: usize, expected: &[u8], actual: &[u8], page_size: usize) {
+        let mut last_page = None;
+        for (i, (x, y)) in std::iter::zip(expected, actual).enumerate() {
+            if x == y {
+                continue;
+            }
+            let address = offset + i;
+            match self.ranges.last_mut() {
+                Some(range) if range.end() == address => {
+                    range.expected.push(*x);
+                    range.actual.push(*y);
+                }
+                _ => self.ranges.push(MismatchRange {
+                    offset: address,
+                    expected: vec![*x],
+                    actual: vec![*y],
+                }),
+            }
+            self.bytes += 1;
+            // Sections are page-aligned, so pages are never shared between calls
+            if last_page != Some(address / page_size) {
+                last_page = Some(address / page_size);
+                self.pages += 1;
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+impl std::fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} bytes in {} ranges across {} pages differ",
+            self.bytes,
+            self.ranges.len(),
+            self.pages
+        )?;
+        if let Some(range) = self.ranges.first() {
+            write!(f, ", first at {:#06X}", range.offset)?;
+        }
+        Ok(())
+    }
+}